@@ -0,0 +1,38 @@
+#! /bin/sh
+# Build the kernel the default machine profile of an architecture boots,
+# from the FirmAE 4.1.17 sources and the defconfigs next to this script:
+#
+#   git clone https://github.com/pr0v3rbs/FirmAE_kernel-v4.1 linux
+#   CROSS_COMPILE=mipsel-linux-gnu- binaries/kernel/build.sh mipsel linux
+#
+# The kernel is written to binaries/kernel/virgin, where profiles/<arch>.toml
+# expects it. "virgin" kernels are built without the firmadyne
+# instrumentation (CONFIG_FIRMADYNE). 4.1 sources predate the compilers of
+# current distributions, a cross gcc 5 to 7 is the safe choice.
+set -e
+
+ARCH_NAME=$1
+SOURCE=$2
+if [ -z "${ARCH_NAME}" ] || [ ! -d "${SOURCE}" ]; then
+  echo "usage: CROSS_COMPILE=<prefix> $0 arm|mips|mipsel <kernel source>" >&2
+  exit 1
+fi
+HERE=$(cd "$(dirname "$0")" && pwd)
+
+case "${ARCH_NAME}" in
+  arm)    KARCH=arm;  CONFIG=firmadyne_arm_4_defconfig; IMAGE=arch/arm/boot/zImage; OUTPUT=zImage.arm.4.virgin ;;
+  mips)   KARCH=mips; CONFIG=firmae_mips_4_defconfig;   IMAGE=vmlinux;              OUTPUT=vmlinux.mips.4.virgin ;;
+  mipsel) KARCH=mips; CONFIG=firmae_mipsel_4_defconfig; IMAGE=vmlinux;              OUTPUT=vmlinux.mipsel.4.virgin ;;
+  *) echo "unknown arch ${ARCH_NAME}" >&2; exit 1 ;;
+esac
+
+BUILD=${BUILD:-${SOURCE}/build/${ARCH_NAME}}
+mkdir -p "${BUILD}"
+cp "${HERE}/configs/${CONFIG}" "${BUILD}/.config"
+"${SOURCE}/scripts/config" --file "${BUILD}/.config" --disable FIRMADYNE
+make -C "${SOURCE}" O="${BUILD}" ARCH=${KARCH} CROSS_COMPILE="${CROSS_COMPILE}" olddefconfig
+make -C "${SOURCE}" O="${BUILD}" ARCH=${KARCH} CROSS_COMPILE="${CROSS_COMPILE}" -j"$(nproc)" "$(basename ${IMAGE})"
+
+mkdir -p "${HERE}/virgin"
+cp "${BUILD}/${IMAGE}" "${HERE}/virgin/${OUTPUT}"
+echo "Built ${HERE}/virgin/${OUTPUT}"
@@ -0,0 +1,81 @@
+#! /busybox sh
+# Guest side of the guest-bridge network mode, FirmAE's network.sh. Started
+# by preInit.sh in the background, after the firmware's rc scripts for all
+# types but default, set up by the files cargo-fae writes into /firmadyne
+# when it generates the image. FAE_NET_* on the kernel command line, as for
+# a learned network, take precedence over the files.
+
+BUSYBOX=/busybox
+ACTION=${FAE_NET_TYPE:-`${BUSYBOX} cat /firmadyne/network_type 2>/dev/null || echo default`}
+NET_BRIDGE=${FAE_NET_BRIDGE:-`${BUSYBOX} cat /firmadyne/net_bridge 2>/dev/null || echo br0`}
+NET_INTERFACE=${FAE_NET_INTERFACE:-`${BUSYBOX} cat /firmadyne/net_interface 2>/dev/null || echo eth0`}
+# FAE_GUEST_IP comes from the kernel command line, the host sits on the tap
+GUEST_IP=${FAE_GUEST_IP:-192.168.1.2/24}
+
+${BUSYBOX} sleep 10
+
+if [ "${ACTION}" = "default" ]; then
+  ${BUSYBOX} brctl addbr ${NET_BRIDGE}
+  ${BUSYBOX} ip addr add ${GUEST_IP} dev ${NET_BRIDGE}
+  ${BUSYBOX} brctl addif ${NET_BRIDGE} ${NET_INTERFACE}
+  ${BUSYBOX} ifconfig ${NET_INTERFACE} 0.0.0.0 up
+  ${BUSYBOX} ip link set ${NET_BRIDGE} up
+elif [ "${ACTION}" != "None" ]; then
+  # netgear WNR2000 bridge command
+  TRIES=60
+  while ! (${BUSYBOX} brctl show | ${BUSYBOX} grep -sq ${NET_BRIDGE}); do
+    if [ ${TRIES} -eq 0 ]; then
+      echo "[fae] network failed, the firmware did not create ${NET_BRIDGE}"
+      exit 1
+    fi
+    TRIES=$((TRIES - 1))
+    ${BUSYBOX} sleep 5
+  done
+
+  ${BUSYBOX} sleep 5
+
+  if [ "${ACTION}" = "normal" ]; then
+    IP=$(${BUSYBOX} ip addr show ${NET_BRIDGE} | ${BUSYBOX} grep -m1 "inet\b" | ${BUSYBOX} awk '{print $2}' | ${BUSYBOX} cut -d/ -f1)
+    # tplink TL-WA860RE_EU_UK_US__V5_171116
+    ${BUSYBOX} ifconfig ${NET_BRIDGE} ${IP}
+    ${BUSYBOX} ifconfig ${NET_INTERFACE} 0.0.0.0 up
+  elif [ "${ACTION}" = "reload" ]; then
+    ${BUSYBOX} ip addr add ${GUEST_IP} dev ${NET_BRIDGE}
+    ${BUSYBOX} ifconfig ${NET_INTERFACE} 0.0.0.0 up
+  elif [ "${ACTION}" = "bridge" ]; then
+    # unexpected intercept by another bridge
+    # netgear WNR2000v5-V1.0.0.34
+    # dlink DIR-505L_FIRMWARE_1.01.ZIP
+    # tplink TL-WA850RE_V5_180228.zip
+    if (${BUSYBOX} brctl show | ${BUSYBOX} grep "eth0"); then
+      WAN_BRIDGE=$(${BUSYBOX} brctl show | ${BUSYBOX} grep "eth0" | ${BUSYBOX} awk '{print $1}')
+      ${BUSYBOX} brctl delif ${WAN_BRIDGE} eth0
+    fi
+    IP=$(${BUSYBOX} ip addr show ${NET_BRIDGE} | ${BUSYBOX} grep -m1 "inet\b" | ${BUSYBOX} awk '{print $2}' | ${BUSYBOX} cut -d/ -f1)
+    ${BUSYBOX} ifconfig ${NET_BRIDGE} ${IP}
+    ${BUSYBOX} brctl addif ${NET_BRIDGE} eth0
+    ${BUSYBOX} ifconfig ${NET_INTERFACE} 0.0.0.0 up
+  elif [ "${ACTION}" = "bridgereload" ]; then
+    if (${BUSYBOX} brctl show | ${BUSYBOX} grep "eth0"); then
+      WAN_BRIDGE=$(${BUSYBOX} brctl show | ${BUSYBOX} grep "eth0" | ${BUSYBOX} awk '{print $1}')
+      ${BUSYBOX} brctl delif ${WAN_BRIDGE} eth0
+    fi
+    ${BUSYBOX} ip addr add ${GUEST_IP} dev ${NET_BRIDGE}
+    ${BUSYBOX} brctl addif ${NET_BRIDGE} eth0
+    ${BUSYBOX} ifconfig ${NET_INTERFACE} 0.0.0.0 up
+  fi
+fi
+# with None the firmware may or may not bring a network up
+[ "${ACTION}" = "None" ] || echo "[fae] network up"
+
+${BUSYBOX} sleep 60
+
+# netgear TL-WR841HP_V2_151124
+while (true); do
+  if ${BUSYBOX} which iptables; then
+    iptables flush | true
+    iptables -F | true
+    iptables -P INPUT ACCEPT | true
+  fi
+  ${BUSYBOX} sleep 5
+done
@@ -16,7 +16,41 @@ ${BUSYBOX} mkdir -p /dev/pts
 ${BUSYBOX} mount -t devpts devpts /dev/pts
 ${BUSYBOX} mount -t tmpfs tmpfs /run
 
-${BUSYBOX} ip addr add 192.168.1.2/24 dev eth0
-${BUSYBOX} ip link set eth0 up
+# FAE_GUEST_IP and FAE_NETWORK come from the kernel command line, one
+# address per parallel instance. A learning boot (FAE_LEARN=<seconds>)
+# leaves the network to the firmware's rc scripts and reports what they set
+# up for cargo-fae to learn from.
+GUEST_DEV=${FAE_GUEST_DEV:-eth0}
 
+# the firmware's own rc scripts, in the background
+start_firmware() {
+  for rc in /etc/init.d/rcS /etc/rc.d/rcS /etc/rc.d/rc.sysinit /sbin/preinit; do
+    if [ -f ${rc} ]; then
+      ${BUSYBOX} sh ${rc} &
+      return
+    fi
+  done
+  echo "[fae] no rc script found to start the firmware"
+}
+
+if [ -n "${FAE_LEARN}" ]; then
+  start_firmware
+  ${BUSYBOX} sleep ${FAE_LEARN}
+  echo "[fae] learn report ip addr"
+  ${BUSYBOX} ip addr
+  echo "[fae] learn report brctl show"
+  ${BUSYBOX} brctl show
+  echo "[fae] learn done"
+elif [ "${FAE_NETWORK}" = "guest-bridge" ]; then
+  # all network types but default work on what the firmware sets up
+  NET_TYPE=${FAE_NET_TYPE:-`${BUSYBOX} cat /firmadyne/network_type 2>/dev/null || echo default`}
+  [ "${NET_TYPE}" = "default" ] || start_firmware
+  ${BUSYBOX} sh /firmadyne/network.sh &
+else
+  ${BUSYBOX} ip addr add ${FAE_GUEST_IP:-192.168.1.2/24} dev ${GUEST_DEV}
+  ${BUSYBOX} ip link set ${GUEST_DEV} up && echo "[fae] network up"
+fi
+
+# the boot monitor of cargo-fae takes this as a successful boot
+echo "[fae] preInit done"
 ${BUSYBOX} sh
\ No newline at end of file
@@ -0,0 +1,160 @@
+//! The kernels, busybox, agent, preInit.sh and machine profiles the tool
+//! ships with, found under one assets root (`binaries/` and `profiles/`).
+//!
+//! The root is taken from, in order: `--assets`, `$FAE_ASSETS`, `assets` in
+//! the [config](crate::Config), `FAE_ASSETS` at build time,
+//! `<exe>/../share/cargo-fae` and the source tree the binary was built from.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::config::Config;
+use crate::emulator::MachineProfile;
+use crate::error::{Error, Result};
+use crate::utils::Arch;
+
+/// Environment variable naming the assets root
+pub const ASSETS_ENV: &str = "FAE_ASSETS";
+
+static ASSETS: OnceLock<Assets> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub struct Assets {
+    pub root: PathBuf,
+    /// where `root` was taken from, for reports
+    pub origin: String,
+}
+
+impl Assets {
+    /// Resolve the assets root once for the whole process. `explicit` wins
+    /// over every other location. Later calls return the first result.
+    pub fn init(explicit: Option<&str>) -> Result<&'static Assets> {
+        if let Some(assets) = ASSETS.get() {
+            return Ok(assets);
+        }
+        let assets = Self::resolve(explicit)?;
+        Ok(ASSETS.get_or_init(|| assets))
+    }
+
+    /// The assets root resolved by `init`, resolving it now if nobody did
+    pub fn get() -> Result<&'static Assets> {
+        Self::init(None)
+    }
+
+    fn resolve(explicit: Option<&str>) -> Result<Assets> {
+        // a location the user chose must be valid, no falling back past it
+        let chosen = explicit.map(|dir| (dir.to_string(), "--assets".to_string()))
+            .or_else(|| std::env::var(ASSETS_ENV).ok().map(|dir| (dir, format!("${}", ASSETS_ENV))))
+            .map(Ok)
+            // the config is only read when neither of the above is set
+            .or_else(|| Config::get().map(|config| config.assets.clone().map(|dir| (dir, "the config".to_string()))).transpose())
+            .transpose()?;
+        if let Some((dir, origin)) = chosen {
+            let assets = Assets { root: PathBuf::from(dir), origin };
+            if !assets.binaries().is_dir() {
+                return Err(Error::Invalid(format!("Assets root {} from {} has no binaries directory",
+                    assets.root.display(), assets.origin)));
+            }
+            return Ok(assets);
+        }
+
+        let mut candidates = Vec::new();
+        if let Some(dir) = option_env!("FAE_ASSETS") {
+            candidates.push((PathBuf::from(dir), "the build time FAE_ASSETS"));
+        }
+        if let Some(exe_dir) = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf)) {
+            candidates.push((exe_dir.join("../share/cargo-fae"), "the install prefix"));
+        }
+        candidates.push((Path::new(env!("CARGO_MANIFEST_DIR")).join(".."), "the source tree"));
+
+        candidates.iter()
+            .find(|(root, _)| root.join("binaries").is_dir())
+            .map(|(root, origin)| Assets { root: root.canonicalize().unwrap_or(root.clone()), origin: origin.to_string() })
+            .ok_or_else(|| {
+                let tried = candidates.iter().map(|(root, _)| root.display().to_string()).collect::<Vec<_>>();
+                Error::Invalid(format!("No assets found in {}, point --assets or ${} at the directory holding binaries/ and profiles/",
+                    tried.join(", "), ASSETS_ENV))
+            })
+    }
+
+    pub fn binaries(&self) -> PathBuf {
+        self.root.join("binaries")
+    }
+
+    pub fn profiles(&self) -> PathBuf {
+        self.root.join("profiles")
+    }
+
+    pub fn kernel_configs(&self) -> PathBuf {
+        self.binaries().join("kernel/configs")
+    }
+
+    /// Builds the kernels of the default profiles from `kernel_configs`
+    pub fn kernel_build_script(&self) -> PathBuf {
+        self.binaries().join("kernel/build.sh")
+    }
+
+    /// Optional, images of an arch without one are generated without it
+    pub fn agent(&self, arch: &Arch) -> PathBuf {
+        self.binaries().join(format!("agent/agent.{}", arch.to_str()))
+    }
+
+    pub fn busybox(&self, arch: &Arch) -> PathBuf {
+        self.binaries().join(format!("busybox/busybox.{}", arch.to_str()))
+    }
+
+    pub fn pre_init(&self) -> PathBuf {
+        self.binaries().join("preInit/preInit.sh")
+    }
+
+    /// Guest side of the guest-bridge network mode
+    pub fn network_script(&self) -> PathBuf {
+        self.binaries().join("preInit/network.sh")
+    }
+
+    /// `path` as written in a profile, relative ones are under the root
+    pub fn resolve_path(&self, path: &str) -> String {
+        if Path::new(path).is_absolute() {
+            path.to_string()
+        } else {
+            self.root.join(path).to_string_lossy().into_owned()
+        }
+    }
+
+    /// Which of the shipped files are there for each architecture, the
+    /// kernel being the one of the arch's default profile
+    pub fn report(&self) -> String {
+        let mut missing = Vec::new();
+        let mut report = format!("Assets in {} (from {})\n", self.root.display(), self.origin);
+        report += &format!("preInit.sh: {}\n", check(self.pre_init(), &mut missing));
+        report += &format!("network.sh: {}\n", check(self.network_script(), &mut missing));
+        report += &format!("{:<8}{:<12}{:<12}{:<12}\n", "arch", "kernel", "busybox", "agent");
+        for arch in Arch::ALL {
+            let kernel = match MachineProfile::load_in(self, arch.to_str()) {
+                Ok(profile) => check(PathBuf::from(profile.kernel), &mut missing),
+                Err(err) => {
+                    missing.push(err.to_string());
+                    "no profile"
+                }
+            };
+            let busybox = check(self.busybox(&arch), &mut missing);
+            // optional, not worth a `missing:` line
+            let agent = if self.agent(&arch).is_file() { "yes" } else { "none" };
+            report += &format!("{:<8}{:<12}{:<12}{:<12}\n", arch.to_str(), kernel, busybox, agent);
+        }
+        for path in missing {
+            report += &format!("missing: {}\n", path);
+        }
+        report
+    }
+}
+
+/// `yes` or `missing` for the report, noting what is missing
+fn check(path: PathBuf, missing: &mut Vec<String>) -> &'static str {
+    if path.is_file() {
+        "yes"
+    } else {
+        missing.push(path.display().to_string());
+        "missing"
+    }
+}
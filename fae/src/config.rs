@@ -0,0 +1,302 @@
+//! Host-wide settings, layered from lowest to highest: built-in defaults,
+//! `~/.config/cargo-fae/config.toml`, `cargo-fae.toml` in the working
+//! directory or one of its parents, the `[config]` table of a task file and
+//! the command line flags. A relative `assets` or `output_dir` is taken
+//! from the directory of the file that sets it.
+//!
+//! ```toml
+//! output_dir = "/data/fae/outputs"
+//! tap = "tap-fae"
+//! host_ip = "10.0.0.1/24"
+//! bridge = "br-fae"
+//! uplink = "eth1"
+//! memory = "512M"
+//! jobs = 4
+//!
+//! [qemu]
+//! qemu-system-mips = "/opt/qemu-8/bin/qemu-system-mips"
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Name of the project config file looked up from the working directory up
+pub const PROJECT_CONFIG: &str = "cargo-fae.toml";
+
+/// Directory extractions go to when none is given
+pub const DEFAULT_OUTPUT_DIR: &str = "../outputs";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// One layer of settings, whatever it leaves out comes from the layers below
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConfigLayer {
+    /// directory holding `binaries/` and `profiles/`
+    pub assets: Option<String>,
+    pub output_dir: Option<String>,
+    /// tap interface the guest network is attached to
+    pub tap: Option<String>,
+    /// address of the host on the tap, with prefix length
+    pub host_ip: Option<String>,
+    /// bridge of the host-bridge network mode
+    pub bridge: Option<String>,
+    /// physical interface the host-bridge mode adds to the bridge
+    pub uplink: Option<String>,
+    /// guest memory when the machine profile sets none
+    pub memory: Option<String>,
+    /// targets of a task file run at the same time
+    pub jobs: Option<usize>,
+    /// QEMU binaries named by the profiles, replaced by another name or path
+    #[serde(default)]
+    pub qemu: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub assets: Option<String>,
+    pub output_dir: String,
+    pub tap: String,
+    pub host_ip: String,
+    pub bridge: String,
+    pub uplink: Option<String>,
+    pub memory: String,
+    pub jobs: usize,
+    pub qemu: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            assets: None,
+            output_dir: DEFAULT_OUTPUT_DIR.to_string(),
+            tap: "tap-qemu".to_string(),
+            host_ip: "192.168.1.1/24".to_string(),
+            bridge: "br-fae".to_string(),
+            uplink: None,
+            memory: "256M".to_string(),
+            jobs: 1,
+            qemu: BTreeMap::new(),
+        }
+    }
+}
+
+/// `~/.config/cargo-fae/config.toml`, honouring `$XDG_CONFIG_HOME`
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("cargo-fae").join("config.toml"))
+}
+
+/// The nearest `cargo-fae.toml` from the working directory up
+pub fn project_config_path() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors().map(|dir| dir.join(PROJECT_CONFIG)).find(|path| path.is_file())
+}
+
+impl ConfigLayer {
+    /// Read a layer from `path`, a missing file is an empty layer. Relative
+    /// paths in it are taken from the directory of the file.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => toml::de::from_str::<ConfigLayer>(&content)
+                .map(|layer| layer.relative_to(path.parent().unwrap_or(Path::new(""))))
+                .map_err(|err| Error::parse(path.display().to_string(), err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(ConfigLayer::default()),
+            Err(err) => Err(Error::io(format!("Failed to read config {}", path.display()), err)),
+        }
+    }
+
+    /// Put `dir` in front of a relative `assets` and `output_dir`
+    pub fn relative_to(mut self, dir: &Path) -> Self {
+        for path in [&mut self.assets, &mut self.output_dir].into_iter().flatten() {
+            if Path::new(path.as_str()).is_relative() {
+                *path = dir.join(path.as_str()).to_string_lossy().into_owned();
+            }
+        }
+        self
+    }
+}
+
+impl Config {
+    /// Put `layer` on top of the settings so far
+    pub fn apply(&mut self, layer: &ConfigLayer) {
+        let ConfigLayer { assets, output_dir, tap, host_ip, bridge, uplink, memory, jobs, qemu } = layer.clone();
+        if assets.is_some() {
+            self.assets = assets;
+        }
+        if uplink.is_some() {
+            self.uplink = uplink;
+        }
+        if let Some(jobs) = jobs {
+            self.jobs = jobs.max(1);
+        }
+        let fields = [
+            (&mut self.output_dir, output_dir),
+            (&mut self.tap, tap),
+            (&mut self.host_ip, host_ip),
+            (&mut self.bridge, bridge),
+            (&mut self.memory, memory),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        self.qemu.extend(qemu);
+    }
+
+    /// Stack the user and project files, then `task` and `cli`, on the
+    /// built-in defaults
+    pub fn load(task: Option<&ConfigLayer>, cli: &ConfigLayer) -> Result<Self> {
+        let mut config = Config::default();
+        for path in [user_config_path(), project_config_path()].into_iter().flatten() {
+            config.apply(&ConfigLayer::load(&path)?);
+        }
+        if let Some(task) = task {
+            config.apply(task);
+        }
+        config.apply(cli);
+        Ok(config)
+    }
+
+    /// Make `config` the one every stage reads. Only the first call has an
+    /// effect, later ones get the config already in place.
+    pub fn init(config: Config) -> &'static Config {
+        CONFIG.get_or_init(|| config)
+    }
+
+    /// The config set by `init`, or the files on top of the defaults
+    pub fn get() -> Result<&'static Config> {
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+        let config = Config::load(None, &ConfigLayer::default())?;
+        Ok(Config::init(config))
+    }
+
+    /// The binary to run for the `qemu` a profile names
+    pub fn qemu_binary<'a>(&'a self, qemu: &'a str) -> &'a str {
+        self.qemu.get(qemu).map_or(qemu, String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(content: &str) -> ConfigLayer {
+        toml::de::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn layers_stack_in_order() {
+        let user = layer(r#"
+            output_dir = "/user/outputs"
+            tap = "tap-user"
+            host_ip = "10.0.0.1/24"
+            bridge = "br-user"
+            memory = "128M"
+        "#);
+        let project = layer(r#"
+            tap = "tap-project"
+            host_ip = "10.1.0.1/24"
+            uplink = "eth1"
+        "#);
+        let task = layer(r#"
+            host_ip = "10.2.0.1/24"
+            jobs = 3
+        "#);
+        let cli = layer(r#"host_ip = "10.3.0.1/24""#);
+
+        let mut config = Config::default();
+        for layer in [&user, &project, &task, &cli] {
+            config.apply(layer);
+        }
+        assert_eq!(config.output_dir, "/user/outputs");
+        assert_eq!(config.tap, "tap-project");
+        assert_eq!(config.host_ip, "10.3.0.1/24");
+        assert_eq!(config.bridge, "br-user");
+        assert_eq!(config.uplink.as_deref(), Some("eth1"));
+        assert_eq!(config.memory, "128M");
+        assert_eq!(config.jobs, 3);
+        // left to the defaults by every layer
+        assert_eq!(config.assets, None);
+    }
+
+    #[test]
+    fn empty_layers_keep_the_defaults() {
+        let mut config = Config::default();
+        config.apply(&ConfigLayer::default());
+        let defaults = Config::default();
+        assert_eq!(config.output_dir, defaults.output_dir);
+        assert_eq!(config.tap, defaults.tap);
+        assert_eq!(config.host_ip, defaults.host_ip);
+        assert_eq!(config.jobs, 1);
+        assert!(config.qemu.is_empty());
+    }
+
+    #[test]
+    fn qemu_binaries_merge_across_layers() {
+        let mut config = Config::default();
+        config.apply(&layer(r#"
+            [qemu]
+            qemu-system-arm = "/opt/qemu/bin/qemu-system-arm"
+            qemu-system-mips = "/opt/qemu/bin/qemu-system-mips"
+        "#));
+        config.apply(&layer(r#"
+            [qemu]
+            qemu-system-mips = "qemu-system-mips-8"
+            qemu-system-mipsel = "qemu-system-mipsel-8"
+        "#));
+        assert_eq!(config.qemu_binary("qemu-system-arm"), "/opt/qemu/bin/qemu-system-arm");
+        assert_eq!(config.qemu_binary("qemu-system-mips"), "qemu-system-mips-8");
+        assert_eq!(config.qemu_binary("qemu-system-mipsel"), "qemu-system-mipsel-8");
+        assert_eq!(config.qemu_binary("qemu-system-x86_64"), "qemu-system-x86_64");
+    }
+
+    #[test]
+    fn zero_jobs_is_one() {
+        let mut config = Config::default();
+        config.apply(&layer("jobs = 4"));
+        config.apply(&layer("jobs = 0"));
+        assert_eq!(config.jobs, 1);
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let layer = ConfigLayer::load(&dir.path().join(PROJECT_CONFIG)).unwrap();
+        assert!(layer.assets.is_none() && layer.output_dir.is_none() && layer.jobs.is_none());
+        assert!(layer.qemu.is_empty());
+    }
+
+    #[test]
+    fn malformed_file_is_a_parse_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(PROJECT_CONFIG);
+        std::fs::write(&path, "jobs = \"many\"\n").unwrap();
+        assert!(matches!(ConfigLayer::load(&path), Err(Error::Parse { .. })));
+        std::fs::write(&path, "tap = [unclosed\n").unwrap();
+        assert!(matches!(ConfigLayer::load(&path), Err(Error::Parse { .. })));
+    }
+
+    #[test]
+    fn relative_paths_are_taken_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(PROJECT_CONFIG);
+        std::fs::write(&path, "assets = \".\"\noutput_dir = \"../outputs\"\ntap = \"tap0\"\n").unwrap();
+        let layer = ConfigLayer::load(&path).unwrap();
+        assert_eq!(layer.assets, Some(dir.path().join(".").to_string_lossy().into_owned()));
+        assert_eq!(layer.output_dir, Some(dir.path().join("../outputs").to_string_lossy().into_owned()));
+        assert_eq!(layer.tap.as_deref(), Some("tap0"));
+
+        std::fs::write(&path, "assets = \"/opt/fae\"\n").unwrap();
+        assert_eq!(ConfigLayer::load(&path).unwrap().assets.as_deref(), Some("/opt/fae"));
+    }
+}
@@ -0,0 +1,238 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::utils::Arch;
+
+const EM_MIPS: u16 = 8;
+const EM_ARM: u16 = 40;
+const EM_AARCH64: u16 = 183;
+
+/// mips ABI bits in e_flags
+const EF_MIPS_ABI2: u32 = 0x20;
+const EF_MIPS_ABI_O32: u32 = 0x1000;
+/// arm EABI version lives in the top byte of e_flags
+const EF_ARM_EABIMASK: u32 = 0xff00_0000;
+
+/// The parts of an ELF header needed to pick an emulation target
+#[derive(Debug)]
+pub struct ElfInfo {
+    pub machine: u16,
+    pub is_64bit: bool,
+    pub little_endian: bool,
+    pub flags: u32,
+}
+
+impl ElfInfo {
+    pub fn describe(&self) -> String {
+        let machine = match self.machine {
+            EM_MIPS => "MIPS".to_string(),
+            EM_ARM => "ARM".to_string(),
+            EM_AARCH64 => "AArch64".to_string(),
+            other => format!("machine {}", other),
+        };
+        let abi = match self.machine {
+            EM_MIPS if self.flags & EF_MIPS_ABI2 != 0 => ", n32",
+            EM_MIPS if self.flags & EF_MIPS_ABI_O32 != 0 || !self.is_64bit => ", o32",
+            EM_ARM if self.flags & EF_ARM_EABIMASK != 0 => ", EABI",
+            EM_ARM => ", OABI",
+            _ => "",
+        };
+        format!(
+            "{}-bit {} {}{}",
+            if self.is_64bit { 64 } else { 32 },
+            if self.little_endian { "LSB" } else { "MSB" },
+            machine,
+            abi
+        )
+    }
+
+    /// Map the header onto one of the architectures we have kernels for
+    pub fn arch(&self) -> Option<Arch> {
+        if self.is_64bit {
+            return None;
+        }
+        match (self.machine, self.little_endian) {
+            (EM_ARM, true) => Some(Arch::Arm),
+            (EM_MIPS, false) if self.flags & EF_MIPS_ABI2 == 0 => Some(Arch::Mips),
+            (EM_MIPS, true) if self.flags & EF_MIPS_ABI2 == 0 => Some(Arch::Mipsel),
+            _ => None,
+        }
+    }
+}
+
+pub fn read_elf_header(path: &Path) -> Option<ElfInfo> {
+    // large enough for the 64-bit header, any real executable is bigger
+    let mut header = [0u8; 64];
+    std::fs::File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if header[..4] != [0x7f, b'E', b'L', b'F'] {
+        return None;
+    }
+    // EI_CLASS and EI_DATA
+    let is_64bit = match header[4] {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    let little_endian = match header[5] {
+        1 => true,
+        2 => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| {
+        let bytes = [header[at], header[at + 1]];
+        if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) }
+    };
+    let u32_at = |at: usize| {
+        let bytes = header[at..at + 4].try_into().unwrap();
+        if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+    };
+    // e_flags sits after the entry/phoff/shoff words, which are wider on 64-bit
+    let flags = u32_at(if is_64bit { 48 } else { 36 });
+
+    Some(ElfInfo { machine: u16_at(18), is_64bit, little_endian, flags })
+}
+
+/// Resolve `path` inside `rootfs`, following symlinks the way the guest would
+/// see them (absolute targets are relative to the rootfs, not the host)
+fn resolve_in_rootfs(rootfs: &Path, path: &Path) -> PathBuf {
+    let mut current = rootfs.join(path.strip_prefix("/").unwrap_or(path));
+    for _ in 0..16 {
+        let Ok(target) = std::fs::read_link(&current) else {
+            break;
+        };
+        current = if target.has_root() {
+            rootfs.join(target.strip_prefix("/").unwrap())
+        } else {
+            current.parent().unwrap().join(target)
+        };
+    }
+    current
+}
+
+/// Binaries that every firmware is likely to ship, checked first
+const PROBES: [&str; 4] = ["/bin/busybox", "/sbin/init", "/bin/sh", "/usr/bin/busybox"];
+
+/// Parse ELF binaries of `rootfs` and return the architecture they target
+pub fn detect_arch(rootfs: &Path) -> Option<Arch> {
+    let mut candidates: Vec<PathBuf> = PROBES.iter().map(PathBuf::from).collect();
+    for dir in ["bin", "sbin", "usr/bin", "usr/sbin", "lib"] {
+        if let Ok(entries) = std::fs::read_dir(rootfs.join(dir)) {
+            candidates.extend(entries.flatten().map(|entry| Path::new(dir).join(entry.file_name())));
+        }
+    }
+
+    // a helper built for another machine must not hide the firmware's own
+    let mut unsupported = None;
+    for candidate in candidates {
+        // never follow a guest symlink onto a host binary
+        let candidate = resolve_in_rootfs(rootfs, &candidate);
+        let Some(elf) = read_elf_header(&candidate) else {
+            continue;
+        };
+        match elf.arch() {
+            Some(arch) => {
+                println!("Detected {:?} from {} ({})", arch, candidate.display(), elf.describe());
+                return Some(arch);
+            }
+            None => {
+                unsupported.get_or_insert_with(|| format!("{}: {}", candidate.display(), elf.describe()));
+            }
+        }
+    }
+    if let Some(unsupported) = unsupported {
+        eprintln!("Unsupported architecture in {}", unsupported);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ELF header for `machine`, padded to a plausible executable
+    fn elf(machine: u16, is_64bit: bool, little_endian: bool, flags: u32) -> Vec<u8> {
+        let mut header = vec![0u8; 256];
+        header[..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+        header[4] = if is_64bit { 2 } else { 1 };
+        header[5] = if little_endian { 1 } else { 2 };
+        let at = if is_64bit { 48 } else { 36 };
+        if little_endian {
+            header[18..20].copy_from_slice(&machine.to_le_bytes());
+            header[at..at + 4].copy_from_slice(&flags.to_le_bytes());
+        } else {
+            header[18..20].copy_from_slice(&machine.to_be_bytes());
+            header[at..at + 4].copy_from_slice(&flags.to_be_bytes());
+        }
+        header
+    }
+
+    fn parse(data: &[u8]) -> Option<ElfInfo> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), data).unwrap();
+        read_elf_header(file.path())
+    }
+
+    #[test]
+    fn big_endian_mips() {
+        let info = parse(&elf(EM_MIPS, false, false, EF_MIPS_ABI_O32 | 0x7000_0007)).unwrap();
+        assert_eq!(info.machine, EM_MIPS);
+        assert!(!info.little_endian);
+        assert_eq!(info.flags, EF_MIPS_ABI_O32 | 0x7000_0007);
+        assert_eq!(info.arch(), Some(Arch::Mips));
+        assert_eq!(info.describe(), "32-bit MSB MIPS, o32");
+    }
+
+    #[test]
+    fn little_endian_mips() {
+        let info = parse(&elf(EM_MIPS, false, true, EF_MIPS_ABI_O32)).unwrap();
+        assert!(info.little_endian);
+        assert_eq!(info.arch(), Some(Arch::Mipsel));
+        assert_eq!(info.describe(), "32-bit LSB MIPS, o32");
+    }
+
+    #[test]
+    fn arm() {
+        let info = parse(&elf(EM_ARM, false, true, 0x0500_0002)).unwrap();
+        assert_eq!(info.arch(), Some(Arch::Arm));
+        assert_eq!(info.describe(), "32-bit LSB ARM, EABI");
+        assert_eq!(parse(&elf(EM_ARM, false, true, 0)).unwrap().describe(), "32-bit LSB ARM, OABI");
+        // no kernel for big endian arm
+        assert_eq!(parse(&elf(EM_ARM, false, false, 0x0500_0002)).unwrap().arch(), None);
+    }
+
+    #[test]
+    fn unsupported_headers() {
+        assert_eq!(parse(&elf(EM_MIPS, false, false, EF_MIPS_ABI2)).unwrap().arch(), None);
+        let info = parse(&elf(EM_MIPS, true, false, EF_MIPS_ABI_O32)).unwrap();
+        assert_eq!(info.flags, EF_MIPS_ABI_O32);
+        assert_eq!(info.arch(), None);
+        assert_eq!(parse(&elf(EM_AARCH64, true, true, 0)).unwrap().describe(), "64-bit LSB AArch64");
+        assert_eq!(parse(&elf(62, true, true, 0)).unwrap().arch(), None);
+    }
+
+    #[test]
+    fn not_elf() {
+        let mut header = elf(EM_ARM, false, true, 0);
+        assert!(parse(&header[..40]).is_none());
+        header[4] = 3;
+        assert!(parse(&header).is_none());
+        assert!(parse(b"#!/bin/sh\necho not an executable\n").is_none());
+    }
+
+    #[test]
+    fn detect_skips_unsupported_binaries() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let root = rootfs.path();
+        for dir in ["bin", "sbin"] {
+            std::fs::create_dir(root.join(dir)).unwrap();
+        }
+        // an x86-64 helper first, then the firmware's own busybox
+        std::fs::write(root.join("bin/busybox"), elf(62, true, true, 0)).unwrap();
+        std::fs::write(root.join("bin/busybox.mipsel"), elf(EM_MIPS, false, true, EF_MIPS_ABI_O32)).unwrap();
+        std::os::unix::fs::symlink("/bin/busybox.mipsel", root.join("sbin/init")).unwrap();
+        assert_eq!(detect_arch(root), Some(Arch::Mipsel));
+
+        std::fs::remove_file(root.join("bin/busybox.mipsel")).unwrap();
+        assert_eq!(detect_arch(root), None);
+    }
+}
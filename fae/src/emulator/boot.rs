@@ -0,0 +1,256 @@
+//! Tell from the serial console whether a firmware booted, panicked or got
+//! stuck, without anyone watching it.
+
+use std::time::{Duration, Instant};
+
+use super::console::ConsoleEvent;
+
+/// Printed by preInit.sh once eth0 is up
+pub const NETWORK_UP_MARKER: &str = "[fae] network up";
+/// Printed by preInit.sh right before it starts the shell
+pub const PREINIT_DONE_MARKER: &str = "[fae] preInit done";
+
+/// `Kernel panic - not syncing` included
+const PANIC_PATTERNS: [&str; 1] = ["Kernel panic"];
+const INIT_FAILURE_PATTERNS: [&str; 4] = [
+    "No working init found",
+    "Failed to execute /",
+    "Unable to mount root fs",
+    "VFS: Cannot open root device",
+];
+/// Lines an init prints when it restarts a service
+const RESPAWN_PATTERNS: [&str; 2] = ["respawn", "Scheduling for restart"];
+/// Restarts seen before the guest counts as stuck in a loop
+const RESPAWN_LIMIT: usize = 5;
+
+/// What the boot came to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootVerdict {
+    /// a success marker showed up, e.g. `prompt` or `preInit done`
+    Booted { marker: String, after: Duration },
+    KernelPanic { line: String },
+    InitFailed { line: String },
+    /// init kept restarting a service
+    RespawnLoop { line: String },
+    /// QEMU exited before anything else was seen
+    Exited { code: Option<i32> },
+    /// nothing conclusive within the boot timeout
+    Timeout,
+}
+
+impl BootVerdict {
+    pub fn is_booted(&self) -> bool {
+        matches!(self, BootVerdict::Booted { .. })
+    }
+
+    /// One or two words for tables
+    pub fn short(&self) -> &str {
+        match self {
+            BootVerdict::Booted { .. } => "booted",
+            BootVerdict::KernelPanic { .. } => "panic",
+            BootVerdict::InitFailed { .. } => "init-failed",
+            BootVerdict::RespawnLoop { .. } => "respawn-loop",
+            BootVerdict::Exited { .. } => "exited",
+            BootVerdict::Timeout => "timeout",
+        }
+    }
+}
+
+impl std::fmt::Display for BootVerdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BootVerdict::Booted { marker, after } => write!(f, "booted, {} after {}s", marker, after.as_secs()),
+            BootVerdict::KernelPanic { line } => write!(f, "kernel panic: {}", line),
+            BootVerdict::InitFailed { line } => write!(f, "init failed: {}", line),
+            BootVerdict::RespawnLoop { line } => write!(f, "respawn loop: {}", line),
+            BootVerdict::Exited { code: Some(code) } => write!(f, "QEMU exited with code {} before the guest booted", code),
+            BootVerdict::Exited { code: None } => write!(f, "QEMU was killed before the guest booted"),
+            BootVerdict::Timeout => write!(f, "no sign of a boot before the timeout"),
+        }
+    }
+}
+
+/// Reads console events until it has a verdict
+pub struct BootMonitor {
+    started: Instant,
+    respawns: usize,
+    /// the only marker taken as a boot, e.g. the end of a learning boot
+    until: Option<&'static str>,
+}
+
+impl Default for BootMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BootMonitor {
+    pub fn new() -> Self {
+        BootMonitor { started: Instant::now(), respawns: 0, until: None }
+    }
+
+    /// Take only `marker` as a boot, prompts and the markers of preInit.sh
+    /// are ignored
+    pub fn until(marker: &'static str) -> Self {
+        BootMonitor { until: Some(marker), ..Self::new() }
+    }
+
+    fn booted(&self, marker: &str) -> Option<BootVerdict> {
+        Some(BootVerdict::Booted { marker: marker.to_string(), after: self.started.elapsed() })
+    }
+
+    pub fn feed(&mut self, event: &ConsoleEvent) -> Option<BootVerdict> {
+        match event {
+            ConsoleEvent::Line(line) => self.line(line),
+            ConsoleEvent::Partial(line) => self.prompt(line),
+        }
+    }
+
+    /// A shell waiting for input, `/ # ` or `~ $ `
+    fn prompt(&self, line: &str) -> Option<BootVerdict> {
+        if self.until.is_some() {
+            return None;
+        }
+        let line = line.trim_start();
+        if line.len() <= 64 && (line.ends_with("# ") || line.ends_with("$ ")) {
+            return self.booted("prompt");
+        }
+        None
+    }
+
+    fn line(&mut self, line: &str) -> Option<BootVerdict> {
+        let line = line.trim();
+        let found = |patterns: &[&str]| patterns.iter().any(|pattern| line.contains(pattern));
+        if found(&PANIC_PATTERNS) {
+            return Some(BootVerdict::KernelPanic { line: line.to_string() });
+        }
+        if found(&INIT_FAILURE_PATTERNS) {
+            return Some(BootVerdict::InitFailed { line: line.to_string() });
+        }
+        if line.contains("respawning too fast") {
+            return Some(BootVerdict::RespawnLoop { line: line.to_string() });
+        }
+        if found(&RESPAWN_PATTERNS) {
+            self.respawns += 1;
+            if self.respawns >= RESPAWN_LIMIT {
+                return Some(BootVerdict::RespawnLoop { line: line.to_string() });
+            }
+        }
+        if let Some(marker) = self.until {
+            return match line.contains(marker) {
+                true => self.booted(marker.trim_start_matches("[fae] ")),
+                false => None,
+            };
+        }
+        if line.contains(PREINIT_DONE_MARKER) {
+            return self.booted("preInit done");
+        }
+        if line.contains(NETWORK_UP_MARKER) {
+            return self.booted("network up");
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(text: &str) -> ConsoleEvent {
+        ConsoleEvent::Line(text.to_string())
+    }
+
+    /// Feed `events` in order and return the first verdict
+    fn first_verdict(monitor: &mut BootMonitor, events: &[ConsoleEvent]) -> Option<BootVerdict> {
+        events.iter().find_map(|event| monitor.feed(event))
+    }
+
+    #[test]
+    fn kernel_panic() {
+        let verdict = first_verdict(&mut BootMonitor::new(), &[
+            line("VFS: Mounted root (ext2 filesystem) on device 254:0."),
+            line("[    2.104000] Kernel panic - not syncing: Attempted to kill init! exitcode=0x0000000b"),
+            line("[fae] preInit done"),
+        ]);
+        assert_eq!(verdict, Some(BootVerdict::KernelPanic {
+            line: "[    2.104000] Kernel panic - not syncing: Attempted to kill init! exitcode=0x0000000b".to_string(),
+        }));
+        let verdict = BootMonitor::new().feed(&line("Kernel panic: Fatal exception in interrupt")).unwrap();
+        assert_eq!(verdict.short(), "panic");
+    }
+
+    #[test]
+    fn init_failure() {
+        let verdict = first_verdict(&mut BootMonitor::new(), &[
+            line("Failed to execute /firmadyne/preInit.sh (error -2).  Attempting defaults..."),
+        ]).unwrap();
+        assert_eq!(verdict.short(), "init-failed");
+
+        let verdict = first_verdict(&mut BootMonitor::new(), &[
+            line("  VFS: Cannot open root device \"sda1\" or unknown-block(0,0): error -6  "),
+        ]);
+        assert_eq!(verdict, Some(BootVerdict::InitFailed {
+            line: "VFS: Cannot open root device \"sda1\" or unknown-block(0,0): error -6".to_string(),
+        }));
+    }
+
+    #[test]
+    fn respawn_limit() {
+        let mut monitor = BootMonitor::new();
+        let respawn = line("init: process '/sbin/getty' (pid 81) exited. Scheduling for restart.");
+        for _ in 1..RESPAWN_LIMIT {
+            assert_eq!(monitor.feed(&respawn), None);
+        }
+        assert_eq!(monitor.feed(&respawn).unwrap().short(), "respawn-loop");
+    }
+
+    #[test]
+    fn respawning_too_fast_is_a_loop_at_once() {
+        let verdict = first_verdict(&mut BootMonitor::new(), &[
+            line("init: Id \"S0\" respawning too fast: disabled for 5 minutes"),
+        ]);
+        assert!(matches!(verdict, Some(BootVerdict::RespawnLoop { .. })));
+    }
+
+    #[test]
+    fn prompt() {
+        let mut monitor = BootMonitor::new();
+        assert_eq!(monitor.feed(&ConsoleEvent::Partial("Please press Enter to activate this console.".to_string())), None);
+        assert_eq!(monitor.feed(&ConsoleEvent::Partial(format!("{}# ", "x".repeat(80)))), None);
+        // a finished line ending like a prompt is just output
+        assert_eq!(monitor.feed(&line("/ # ")), None);
+        let verdict = monitor.feed(&ConsoleEvent::Partial("\r\n/ # ".to_string())).unwrap();
+        assert!(matches!(verdict, BootVerdict::Booted { ref marker, .. } if marker == "prompt"));
+        assert!(verdict.is_booted());
+        assert!(BootMonitor::new().feed(&ConsoleEvent::Partial("~ $ ".to_string())).is_some());
+    }
+
+    #[test]
+    fn preinit_markers() {
+        let booted = |text: &str| match BootMonitor::new().feed(&line(text)) {
+            Some(BootVerdict::Booted { marker, .. }) => Some(marker),
+            _ => None,
+        };
+        assert_eq!(booted("[fae] network up").as_deref(), Some("network up"));
+        assert_eq!(booted("[fae] preInit done").as_deref(), Some("preInit done"));
+        assert_eq!(booted("Starting pid 1, console /dev/ttyS0"), None);
+    }
+
+    #[test]
+    fn until_waits_for_its_marker() {
+        let mut monitor = BootMonitor::until("[fae] learn done");
+        assert_eq!(first_verdict(&mut monitor, &[
+            line("[fae] network up"),
+            line("[fae] preInit done"),
+            ConsoleEvent::Partial("/ # ".to_string()),
+        ]), None);
+        let verdict = monitor.feed(&line("[fae] learn done")).unwrap();
+        assert!(matches!(verdict, BootVerdict::Booted { ref marker, .. } if marker == "learn done"));
+    }
+
+    #[test]
+    fn until_still_sees_failures() {
+        let mut monitor = BootMonitor::until("[fae] learn done");
+        assert_eq!(monitor.feed(&line("Kernel panic - not syncing: VFS")).unwrap().short(), "panic");
+    }
+}
@@ -0,0 +1,135 @@
+//! The serial console of a headless guest, written to a log file with a
+//! timestamp per line and shown on the terminal as well with `tee`.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+use crate::error::{Error, Result};
+
+/// Where the guest console goes
+#[derive(Debug, Clone, Default)]
+pub enum Console {
+    /// the terminal, which also types into the guest
+    #[default]
+    Interactive,
+    /// QEMU runs detached, the console goes to `log` and with `tee` to the
+    /// terminal too
+    Headless { log: PathBuf, tee: bool },
+}
+
+/// Start time of a run as it appears in file names, e.g. `20261018T110102Z`
+pub fn run_stamp() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string().replace(['-', ':'], "")
+}
+
+/// `<image>.<run stamp>.serial.log`, next to the image
+pub fn default_log(image: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}.serial.log", image, run_stamp()))
+}
+
+/// What the console pump passes on to a watcher such as the boot monitor
+#[derive(Debug, Clone)]
+pub enum ConsoleEvent {
+    Line(String),
+    /// the start of a line, e.g. a shell prompt waiting for input
+    Partial(String),
+}
+
+/// Lines of one stream, stamped and appended to the shared log
+struct LineWriter {
+    log: Option<Arc<Mutex<File>>>,
+    prefix: &'static str,
+    line: Vec<u8>,
+    /// when the first byte of `line` arrived
+    stamp: Option<humantime::Rfc3339Timestamp>,
+    events: Option<Sender<ConsoleEvent>>,
+}
+
+impl LineWriter {
+    fn new(log: Option<Arc<Mutex<File>>>, prefix: &'static str, events: Option<Sender<ConsoleEvent>>) -> Self {
+        LineWriter { log, prefix, line: Vec::new(), stamp: None, events }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            match byte {
+                b'\n' => self.flush(),
+                b'\r' => {}
+                byte => {
+                    self.stamp.get_or_insert_with(|| humantime::format_rfc3339_millis(SystemTime::now()));
+                    self.line.push(byte);
+                }
+            }
+        }
+        if !self.line.is_empty() {
+            self.send(ConsoleEvent::Partial(String::from_utf8_lossy(&self.line).into_owned()));
+        }
+    }
+
+    fn flush(&mut self) {
+        let stamp = self.stamp.take().unwrap_or_else(|| humantime::format_rfc3339_millis(SystemTime::now()));
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        if let Some(log) = &self.log {
+            let mut log = log.lock().unwrap_or_else(|err| err.into_inner());
+            // a full disk should not stop the guest, the log is best effort
+            let _ = writeln!(log, "[{}] {}{}", stamp, self.prefix, line);
+        }
+        self.send(ConsoleEvent::Line(line));
+        self.line.clear();
+    }
+
+    fn send(&mut self, event: ConsoleEvent) {
+        // the watcher may have seen enough and hung up
+        if let Some(events) = &self.events {
+            if events.send(event).is_err() {
+                self.events = None;
+            }
+        }
+    }
+}
+
+/// Copy `stream` into the log line by line until it closes, and raw to the
+/// terminal with `tee`
+fn pump(mut stream: impl Read + Send + 'static, mut writer: LineWriter, tee: bool) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            if tee {
+                let mut stdout = std::io::stdout().lock();
+                let _ = stdout.write_all(&buffer[..read]);
+                let _ = stdout.flush();
+            }
+            writer.feed(&buffer[..read]);
+        }
+        if !writer.line.is_empty() {
+            writer.flush();
+        }
+    })
+}
+
+pub fn create_log(log: &Path) -> Result<File> {
+    File::create(log).map_err(|err| Error::io(format!("Failed to create serial log {}", log.display()), err))
+}
+
+/// Pass on QEMU's stdout, which carries the serial console with
+/// `-nographic`, to `log`, the terminal with `tee` and `events`. A piped
+/// stderr is logged marked `qemu:`. Join the handles after QEMU exits to
+/// have every line written.
+pub fn capture(stdout: impl Read + Send + 'static, stderr: Option<impl Read + Send + 'static>,
+               log: Option<File>, tee: bool, events: Option<Sender<ConsoleEvent>>) -> Vec<JoinHandle<()>> {
+    let log = log.map(|log| Arc::new(Mutex::new(log)));
+    let mut pumps = vec![pump(stdout, LineWriter::new(log.clone(), "", events), tee)];
+    if let Some(stderr) = stderr {
+        pumps.push(pump(stderr, LineWriter::new(log, "qemu: ", None), tee));
+    }
+    pumps
+}
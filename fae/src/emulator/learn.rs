@@ -0,0 +1,461 @@
+//! Learn the network a firmware sets up, as FirmAE does with its first
+//! boot: preInit.sh runs the firmware's own rc scripts, reports `ip addr`
+//! and `brctl show` after a while, and the serial log is read back for
+//! those reports and for the bridge and address commands and kernel
+//! messages along the way. Later runs rebuild the firmware's LAN from the
+//! plan derived from it.
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::console::run_stamp;
+use crate::error::{Error, Result};
+use crate::utils::{GuestNetwork, GuestNetworkType};
+
+/// Printed by preInit.sh after the reports of a learning boot
+pub const LEARN_DONE_MARKER: &str = "[fae] learn done";
+/// Printed by preInit.sh before the output of each report
+const REPORT_MARKER: &str = "[fae] learn report ";
+
+/// Interfaces and bridges seen in a learning boot
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Observed {
+    /// IPv4 addresses with prefix length, by interface
+    #[serde(default)]
+    pub addresses: BTreeMap<String, Vec<String>>,
+    /// ports, by bridge
+    #[serde(default)]
+    pub bridges: BTreeMap<String, Vec<String>>,
+}
+
+impl Observed {
+    /// Read the serial log of a learning boot. The final reports win over
+    /// what the console showed before, except for bridges when the guest's
+    /// busybox has no `brctl`.
+    pub fn from_log(log: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(log)
+            .map_err(|err| Error::io(format!("Failed to read serial log {}", log.display()), err))?;
+        Ok(Self::parse(&content))
+    }
+
+    pub fn parse(console: &str) -> Self {
+        let mut seen = Observed::default();
+        let mut reported = Observed::default();
+        let mut report = "";
+        // interface or bridge the lines of a report are about
+        let mut current = None;
+        for line in console.lines() {
+            let line = strip_stamp(line);
+            if let Some(name) = line.trim().strip_prefix(REPORT_MARKER) {
+                report = if name.starts_with("ip") { "ip" } else if name.starts_with("brctl") { "brctl" } else { "" };
+                current = None;
+                continue;
+            }
+            if line.contains(LEARN_DONE_MARKER) {
+                report = "";
+                continue;
+            }
+            match report {
+                "ip" => reported.ip_addr_line(line, &mut current),
+                "brctl" => reported.brctl_line(line, &mut current),
+                _ => seen.console_line(line),
+            }
+        }
+        if !reported.addresses.is_empty() {
+            seen.addresses = reported.addresses;
+        }
+        for (bridge, ports) in reported.bridges {
+            // `master` in `ip addr` or the kernel may know of more ports
+            for port in ports {
+                seen.add_port(&bridge, &port);
+            }
+            seen.bridges.entry(bridge).or_default();
+        }
+        seen
+    }
+
+    fn add_address(&mut self, interface: &str, address: &str) {
+        let addresses = self.addresses.entry(interface.to_string()).or_default();
+        if !addresses.iter().any(|known| known == address) {
+            addresses.push(address.to_string());
+        }
+    }
+
+    fn add_port(&mut self, bridge: &str, port: &str) {
+        let ports = self.bridges.entry(bridge.to_string()).or_default();
+        if !ports.iter().any(|known| known == port) {
+            ports.push(port.to_string());
+        }
+    }
+
+    /// `brctl addbr|addif`, `ifconfig <if> <ip>`, `ip addr add` echoed by
+    /// the firmware's scripts, and kernel lines like
+    /// `br0: port 1(eth0) entered forwarding state`
+    fn console_line(&mut self, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        for (i, word) in words.iter().enumerate() {
+            let rest = &words[i + 1..];
+            match (*word, rest) {
+                ("brctl", ["addbr", bridge, ..]) => {
+                    self.bridges.entry(bridge.to_string()).or_default();
+                }
+                ("brctl", ["addif", bridge, port, ..]) => self.add_port(bridge, port),
+                ("ifconfig", [interface, address, ..]) if address.parse::<Ipv4Addr>().is_ok_and(|address| !address.is_unspecified()) => {
+                    let prefix = rest.iter().skip_while(|word| **word != "netmask").nth(1)
+                        .and_then(|mask| mask.parse::<Ipv4Addr>().ok())
+                        .map_or(24, |mask| u32::from(mask).count_ones());
+                    self.add_address(interface, &format!("{}/{}", address, prefix));
+                }
+                ("ip", [object, "add", address, "dev", interface, ..]) if object.starts_with('a') && is_ipv4(address) => {
+                    let address = match address.contains('/') {
+                        true => address.to_string(),
+                        false => format!("{}/32", address),
+                    };
+                    self.add_address(interface, &address);
+                }
+                (bridge, ["port", port, ..]) if bridge.ends_with(':') => {
+                    if let Some((_, port)) = port.trim_end_matches(')').split_once('(') {
+                        self.add_port(bridge.trim_end_matches(':'), port);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// `2: eth0: <BROADCAST,...> mtu 1500 ... master br0` and
+    /// `    inet 192.168.0.1/24 brd ...` of `ip addr`
+    fn ip_addr_line(&mut self, line: &str, interface: &mut Option<String>) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [index, name, ..] if index.ends_with(':') && index[..index.len() - 1].parse::<u32>().is_ok() => {
+                // a VLAN shows as eth0.1@eth0
+                let name = name.trim_end_matches(':').split('@').next().unwrap_or_default();
+                if let Some(bridge) = words.iter().skip_while(|word| **word != "master").nth(1) {
+                    self.add_port(bridge, name);
+                }
+                *interface = Some(name.to_string());
+            }
+            ["inet", address, ..] if is_ipv4(address) => {
+                if let Some(interface) = interface {
+                    self.add_address(interface, address);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// `br0  8000.525400123456  no  eth0` of `brctl show`, further ports on
+    /// lines of their own
+    fn brctl_line(&mut self, line: &str, bridge: &mut Option<String>) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if line.starts_with("bridge name") || words.is_empty() || words[0].ends_with(':') {
+            return;
+        }
+        if line.starts_with(char::is_whitespace) {
+            if let (Some(bridge), [port]) = (bridge.as_deref(), words.as_slice()) {
+                self.add_port(bridge, port);
+            }
+            return;
+        }
+        if words.len() >= 3 {
+            self.bridges.entry(words[0].to_string()).or_default();
+            if let Some(port) = words.get(3) {
+                self.add_port(words[0], port);
+            }
+            *bridge = Some(words[0].to_string());
+        }
+    }
+}
+
+/// `<image>.<run stamp>.learn.log`, next to the image
+pub fn learn_log(image: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}.learn.log", image, run_stamp()))
+}
+
+/// Drop the `[<time>] ` the console logger puts in front of each line
+fn strip_stamp(line: &str) -> &str {
+    match line.strip_prefix('[').and_then(|rest| rest.split_once("] ")) {
+        Some((stamp, rest)) if stamp.contains('T') => rest,
+        _ => line,
+    }
+}
+
+/// `a.b.c.d` or `a.b.c.d/n`
+fn is_ipv4(address: &str) -> bool {
+    let (address, prefix) = address.split_once('/').unwrap_or((address, "32"));
+    address.parse::<Ipv4Addr>().is_ok() && prefix.parse::<u32>().is_ok_and(|prefix| prefix <= 32)
+}
+
+/// The guest network a learning boot came to, and the host address that
+/// reaches it, kept in the image info for the `Learned` network mode
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NetworkPlan {
+    /// the bridge or interface holding the firmware's LAN address
+    pub lan: String,
+    /// the firmware's LAN address, with prefix length
+    pub guest_ip: String,
+    /// address of the tap in the same subnet
+    pub host_ip: String,
+    /// how the guest network script rebuilds the LAN bridge, None when the
+    /// address sits on `lan` itself
+    pub guest_network: Option<GuestNetwork>,
+    pub observed: Observed,
+}
+
+impl NetworkPlan {
+    /// Take the LAN from what was observed: a bridge with an address, the
+    /// one holding eth0 first, else an interface with one, eth0 first.
+    pub fn derive(observed: Observed) -> Result<Self> {
+        let rank = |name: &str| {
+            let ports = observed.bridges.get(name);
+            match ports {
+                Some(ports) if ports.iter().any(|port| port == "eth0") => 0,
+                Some(_) => 1,
+                None if name == "eth0" => 2,
+                None if name.starts_with("eth") => 3,
+                None => 4,
+            }
+        };
+        let (lan, guest_ip) = observed.addresses.iter()
+            .filter_map(|(name, addresses)| {
+                let address = addresses.iter().find(|address| !address.starts_with("127."))?;
+                Some((name, address))
+            })
+            .min_by_key(|(name, _)| rank(name))
+            .ok_or_else(|| Error::Invalid("The firmware gave no interface an address, try waiting longer".to_string()))?;
+        let guest_ip = &widen(guest_ip);
+        let host_ip = host_address(guest_ip)
+            .ok_or_else(|| Error::Invalid(format!("No host address next to {} of {}", guest_ip, lan)))?;
+
+        let guest_network = observed.bridges.get(lan).map(|ports| {
+            // QEMU only gives the guest ethernet NICs, a bridge of wireless
+            // ports alone gets eth0
+            let interface = ports.iter().find(|port| port.starts_with("eth"));
+            // the bridge is created rather than left to the firmware's rc
+            // scripts, which may fail to come up the same way twice
+            GuestNetwork {
+                network_type: GuestNetworkType::Default,
+                bridge: lan.clone(),
+                interface: interface.map_or_else(|| "eth0".to_string(), String::clone),
+            }
+        });
+        Ok(NetworkPlan { lan: lan.clone(), guest_ip: guest_ip.clone(), host_ip, guest_network, observed })
+    }
+
+    /// What preInit.sh and the guest network script are told on the kernel
+    /// command line to set the plan up, FAE_GUEST_IP aside
+    pub fn guest_env(&self) -> Vec<String> {
+        match &self.guest_network {
+            Some(network) => vec![
+                "FAE_NETWORK=guest-bridge".to_string(),
+                format!("FAE_NET_TYPE={}", network.network_type.to_str()),
+                format!("FAE_NET_BRIDGE={}", network.bridge),
+                format!("FAE_NET_INTERFACE={}", network.interface),
+            ],
+            None => vec!["FAE_NETWORK=tap".to_string(), format!("FAE_GUEST_DEV={}", self.lan)],
+        }
+    }
+}
+
+/// A /31 or /32 has no room for the host, make it a /24
+fn widen(address: &str) -> String {
+    match address.split_once('/') {
+        Some((address, prefix)) if prefix.parse::<u32>().is_ok_and(|prefix| prefix > 30) => format!("{}/24", address),
+        _ => address.to_string(),
+    }
+}
+
+/// The first address of the subnet of `guest_ip`, or the second when the
+/// guest has the first
+fn host_address(guest_ip: &str) -> Option<String> {
+    let (address, prefix) = guest_ip.split_once('/')?;
+    let address = u32::from(address.parse::<Ipv4Addr>().ok()?);
+    let prefix = prefix.parse::<u32>().ok().filter(|prefix| (1..=30).contains(prefix))?;
+    let network = address & (u32::MAX << (32 - prefix));
+    let host = if network + 1 == address { network + 2 } else { network + 1 };
+    Some(format!("{}/{}", Ipv4Addr::from(host), prefix))
+}
+
+impl std::fmt::Display for NetworkPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "guest {} on {}", self.guest_ip, self.lan)?;
+        if let Some(network) = &self.guest_network {
+            write!(f, " bridging {}", network.interface)?;
+        }
+        write!(f, ", host {} on the tap", self.host_ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serial log of a learning boot of a router bridging eth0 and a
+    /// wireless interface, as the console logger writes it
+    const LEARN_LOG: &str = "\
+[2026-10-18T10:00:01.120Z] Linux version 4.1.17+ (gcc version 5.3.0)
+[2026-10-18T10:00:09.480Z] brctl addbr br0
+[2026-10-18T10:00:09.512Z] brctl addif br0 eth0
+[2026-10-18T10:00:09.530Z] ifconfig br0 192.168.0.1 netmask 255.255.255.0 up
+[2026-10-18T10:00:09.610Z] br0: port 1(eth0) entered forwarding state
+[2026-10-18T10:00:09.700Z] br0: port 2(ra0) entered forwarding state
+[2026-10-18T10:00:10.002Z] ifconfig eth1 0.0.0.0 up
+[2026-10-18T10:01:00.001Z] [fae] learn report ip addr
+[2026-10-18T10:01:00.002Z] 1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536 qdisc noqueue
+[2026-10-18T10:01:00.003Z]     link/loopback 00:00:00:00:00:00 brd 00:00:00:00:00:00
+[2026-10-18T10:01:00.004Z]     inet 127.0.0.1/8 scope host lo
+[2026-10-18T10:01:00.005Z] 2: eth0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc pfifo_fast master br0 qlen 1000
+[2026-10-18T10:01:00.006Z]     link/ether 52:54:00:12:34:56 brd ff:ff:ff:ff:ff:ff
+[2026-10-18T10:01:00.007Z] 3: eth0.2@eth0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue
+[2026-10-18T10:01:00.008Z]     inet 10.0.2.15/24 brd 10.0.2.255 scope global eth0.2
+[2026-10-18T10:01:00.009Z] 4: br0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue
+[2026-10-18T10:01:00.010Z]     inet 192.168.0.1/24 brd 192.168.0.255 scope global br0
+[2026-10-18T10:01:00.011Z] [fae] learn report brctl show
+[2026-10-18T10:01:00.012Z] bridge name\tbridge id\t\tSTP enabled\tinterfaces
+[2026-10-18T10:01:00.013Z] br0\t\t8000.525400123456\tno\t\teth0
+[2026-10-18T10:01:00.014Z] \t\t\t\t\t\t\tra0
+[2026-10-18T10:01:00.015Z] [fae] learn done
+";
+
+    fn map(entries: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        entries.iter()
+            .map(|(name, values)| (name.to_string(), values.iter().map(|value| value.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_prefers_the_reports() {
+        let observed = Observed::parse(LEARN_LOG);
+        assert_eq!(observed.addresses, map(&[
+            ("br0", &["192.168.0.1/24"]),
+            ("eth0.2", &["10.0.2.15/24"]),
+            ("lo", &["127.0.0.1/8"]),
+        ]));
+        assert_eq!(observed.bridges, map(&[("br0", &["eth0", "ra0"])]));
+    }
+
+    #[test]
+    fn parse_without_reports_keeps_the_console() {
+        let console = LEARN_LOG.split("[fae] learn report").next().unwrap();
+        let observed = Observed::parse(console);
+        assert_eq!(observed.addresses, map(&[("br0", &["192.168.0.1/24"])]));
+        assert_eq!(observed.bridges, map(&[("br0", &["eth0", "ra0"])]));
+    }
+
+    #[test]
+    fn console_line_reads_commands_and_kernel_messages() {
+        let mut observed = Observed::default();
+        observed.console_line("+ brctl addbr br1");
+        observed.console_line("brctl addif br1 eth1");
+        observed.console_line("ifconfig eth1 10.0.0.1 netmask 255.255.0.0");
+        observed.console_line("ifconfig eth2 10.1.0.1 up");
+        observed.console_line("ifconfig eth3 0.0.0.0 up");
+        observed.console_line("ip addr add 172.16.0.1/12 dev br1");
+        observed.console_line("ip a add 172.17.0.1 dev eth4");
+        observed.console_line("ip addr add fe80::1/64 dev br1");
+        observed.console_line("[    4.512000] br1: port 2(wlan0) entered learning state");
+        observed.console_line("br1: port 3 entered disabled state");
+        assert_eq!(observed.addresses, map(&[
+            ("br1", &["172.16.0.1/12"]),
+            ("eth1", &["10.0.0.1/16"]),
+            ("eth2", &["10.1.0.1/24"]),
+            ("eth4", &["172.17.0.1/32"]),
+        ]));
+        assert_eq!(observed.bridges, map(&[("br1", &["eth1", "wlan0"])]));
+    }
+
+    #[test]
+    fn ip_addr_line_tracks_the_interface() {
+        let mut observed = Observed::default();
+        let mut interface = None;
+        observed.ip_addr_line("    inet 10.9.9.9/24 scope global nowhere", &mut interface);
+        observed.ip_addr_line("5: eth1.10@eth1: <BROADCAST,UP> mtu 1500 master br1", &mut interface);
+        assert_eq!(interface.as_deref(), Some("eth1.10"));
+        observed.ip_addr_line("    inet 192.168.10.1/24 brd 192.168.10.255 scope global eth1.10", &mut interface);
+        observed.ip_addr_line("    inet6 fe80::5054:ff:fe12:3456/64 scope link", &mut interface);
+        observed.ip_addr_line("    inet 192.168.10.1/24 brd 192.168.10.255 scope global eth1.10", &mut interface);
+        assert_eq!(observed.addresses, map(&[("eth1.10", &["192.168.10.1/24"])]));
+        assert_eq!(observed.bridges, map(&[("br1", &["eth1.10"])]));
+    }
+
+    #[test]
+    fn brctl_line_reads_bridges_and_continued_ports() {
+        let mut observed = Observed::default();
+        let mut bridge = None;
+        for line in [
+            "bridge name\tbridge id\t\tSTP enabled\tinterfaces",
+            "\t\t\t\t\t\t\teth9",
+            "br0\t\t8000.525400123456\tno\t\teth0",
+            "\t\t\t\t\t\t\twlan0",
+            "br1\t\t8000.000000000000\tno",
+            "brctl: not found",
+            "",
+        ] {
+            observed.brctl_line(line, &mut bridge);
+        }
+        assert_eq!(observed.bridges, map(&[("br0", &["eth0", "wlan0"]), ("br1", &[])]));
+    }
+
+    #[test]
+    fn widen_makes_room_for_the_host() {
+        assert_eq!(widen("192.168.0.1/32"), "192.168.0.1/24");
+        assert_eq!(widen("192.168.0.1/31"), "192.168.0.1/24");
+        assert_eq!(widen("192.168.0.1/30"), "192.168.0.1/30");
+        assert_eq!(widen("10.0.0.1/8"), "10.0.0.1/8");
+    }
+
+    #[test]
+    fn host_address_avoids_the_guest() {
+        assert_eq!(host_address("192.168.0.1/24").as_deref(), Some("192.168.0.2/24"));
+        assert_eq!(host_address("192.168.0.254/24").as_deref(), Some("192.168.0.1/24"));
+        assert_eq!(host_address("10.1.2.3/8").as_deref(), Some("10.0.0.1/8"));
+        assert_eq!(host_address("192.168.0.1/32"), None);
+        assert_eq!(host_address("192.168.0.1/0"), None);
+        assert_eq!(host_address("192.168.0.1"), None);
+        assert_eq!(host_address("not.an.ip/24"), None);
+    }
+
+    #[test]
+    fn derive_takes_the_bridge_holding_eth0() {
+        let plan = NetworkPlan::derive(Observed::parse(LEARN_LOG)).unwrap();
+        assert_eq!(plan.lan, "br0");
+        assert_eq!(plan.guest_ip, "192.168.0.1/24");
+        assert_eq!(plan.host_ip, "192.168.0.2/24");
+        let network = plan.guest_network.unwrap();
+        assert_eq!(network.bridge, "br0");
+        assert_eq!(network.interface, "eth0");
+    }
+
+    #[test]
+    fn derive_bridges_eth0_for_wireless_only_bridges() {
+        let observed = Observed {
+            addresses: map(&[("br0", &["192.168.1.1/24"])]),
+            bridges: map(&[("br0", &["ra0", "wlan0"])]),
+        };
+        let plan = NetworkPlan::derive(observed).unwrap();
+        assert_eq!(plan.guest_network.unwrap().interface, "eth0");
+    }
+
+    #[test]
+    fn derive_uses_an_interface_without_bridge() {
+        let observed = Observed {
+            addresses: map(&[("lo", &["127.0.0.1/8"]), ("ra0", &["10.0.0.1/24"]), ("eth1", &["192.168.2.1/32"])]),
+            bridges: BTreeMap::new(),
+        };
+        let plan = NetworkPlan::derive(observed).unwrap();
+        assert_eq!(plan.lan, "eth1");
+        assert_eq!(plan.guest_ip, "192.168.2.1/24");
+        assert_eq!(plan.guest_network, None);
+        assert_eq!(plan.guest_env(), vec!["FAE_NETWORK=tap", "FAE_GUEST_DEV=eth1"]);
+    }
+
+    #[test]
+    fn derive_fails_without_addresses() {
+        let observed = Observed { addresses: map(&[("lo", &["127.0.0.1/8"])]), bridges: BTreeMap::new() };
+        assert!(NetworkPlan::derive(observed).is_err());
+    }
+}
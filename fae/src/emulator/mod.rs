@@ -1,71 +1,347 @@
-use std::process::{Command, Stdio};
-use crate::utils::Arch;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use crate::assets::Assets;
+use crate::config::Config;
+use crate::error::{run, Error, Result};
+use crate::utils::{Arch, ImageInfo, ImageType};
 
 pub mod utils;
+mod boot;
+mod console;
+mod learn;
+mod overlay;
+mod profile;
+mod qmp;
 use utils::*;
+pub use profile::{MachineProfile, ProfileOverrides};
+pub use boot::{BootMonitor, BootVerdict};
+pub use console::{default_log, run_stamp, Console, ConsoleEvent};
+pub use learn::{learn_log, NetworkPlan, Observed};
+pub use overlay::{overlay_path, Overlay};
+pub use qmp::{qmp_socket, BlockDevice, Qmp, VmStatus};
+pub use utils::{Instance, NetworkMode};
 
-pub fn run_emulation(image: &str, arch: &Arch, debug: &bool) {
-    init_network();
-    let kernel;
-    let qemu;
-    let machine;
-    let drive_if;
-    let root_device;
-    let net_device;
-    match arch {
-        Arch::Arm => {
-            qemu = "qemu-system-arm";
-            kernel = "../binaries/kernel/virgin/zImage.arm.4.virgin";
-            machine = "virt-2.10";
-            root_device = "/dev/vda1";
-            drive_if = "none";
-            net_device = "virtio-net-device";
+/// How long a run may take and what to watch for
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// QEMU is stopped after this long, which is not an error
+    pub timeout: Option<Duration>,
+    /// watch the console for a [`BootVerdict`], `Timeout` after this long
+    pub boot_timeout: Option<Duration>,
+    /// wait for gdb on port 1234 before the guest starts
+    pub debug: bool,
+    /// start from this VM snapshot of the image instead of booting
+    pub loadvm: Option<String>,
+    /// once the guest booted, save it as a VM snapshot of this name into the
+    /// image and stop QEMU
+    pub savevm: Option<String>,
+    /// let the guest write into an overlay instead of the image
+    pub overlay: Overlay,
+    pub network: NetworkMode,
+    /// a learning boot: the firmware's rc scripts run and the network they
+    /// set up is reported on the console after this long, see [`NetworkPlan`]
+    pub learn: Option<Duration>,
+}
+
+/// Boot `image` in QEMU with the machine profile named `profile`, or the
+/// one of its architecture, on the tap and addresses of `instance`. A tap
+/// interface created for the run is removed when QEMU exits. QEMU serves
+/// QMP on `instance.qmp` while it runs, see [`Qmp`].
+///
+/// With a boot timeout the console is watched and the verdict returned. A
+/// headless QEMU is stopped once there is one, an interactive one is left
+/// running.
+///
+/// Snapshots are saved into and loaded from qcow2 images only. The image
+/// info records the profile of each, which loading it defaults to; the
+/// guest comes back with the address it had when saved.
+///
+/// With an overlay the image is only read, so runs on their own overlays
+/// can share it.
+///
+/// The `Learned` network mode takes the addresses of the guest and the tap
+/// from the plan a learning boot left in the image info.
+pub fn run_emulation(image: &str, arch: &Option<Arch>, profile: Option<&str>, overrides: &ProfileOverrides,
+                     instance: &Instance, options: &RunOptions) -> Result<Option<BootVerdict>> {
+    if options.loadvm.is_some() || options.savevm.is_some() {
+        if !matches!(ImageType::detect(image), ImageType::Qcow2) {
+            return Err(Error::Invalid(format!("Snapshots need a qcow2 image, {} is raw", image)));
+        }
+        if options.loadvm.is_some() && options.savevm.is_some() {
+            return Err(Error::Invalid("A snapshot is saved from a cold boot, not from another snapshot".to_string()));
         }
-        Arch::Mips => {
-            qemu = "qemu-system-mips";
-            kernel = "../binaries/kernel/virgin/vmlinux.mips.4.virgin"; // 3.2.0.malta
-            machine = "malta";
-            root_device = "/dev/sda1";
-            drive_if = "ide";
-            net_device = "pcnet";
+        if options.overlay != Overlay::Off {
+            return Err(Error::Invalid("Snapshots live in the image itself, they can't be used with an overlay".to_string()));
         }
-        Arch::Mipsel => {
-            qemu = "qemu-system-mipsel";
-            kernel = "../binaries/kernel/vmlinux.mipsel.4";
-            machine = "";
-            root_device = "";
-            drive_if = "";
-            net_device = "e1000";
+    }
+    let snapshot_profile = options.loadvm.as_ref()
+        .and_then(|name| ImageInfo::load(image).and_then(|mut info| info.snapshots.remove(name)));
+    let profile_name = match profile.map(str::to_string).or(snapshot_profile) {
+        Some(profile) => profile,
+        None => arch.clone()
+            .or_else(|| ImageInfo::load(image).and_then(|info| info.arch))
+            .ok_or_else(|| Error::Invalid(format!("Unknown architecture of {}, please specify it with --arch", image)))?
+            .to_str()
+            .to_string(),
+    };
+    let learned = match options.network {
+        NetworkMode::Learned => Some(ImageInfo::load(image).and_then(|info| info.learned_network)
+            .ok_or_else(|| Error::Invalid(format!("No network learned for {}, run `cargo-fae learn` on it first", image)))?),
+        _ => None,
+    };
+    let bridged = options.network == NetworkMode::GuestBridge
+        || learned.as_ref().is_some_and(|plan| plan.guest_network.is_some());
+    // both came with the guest network script
+    if (bridged || options.learn.is_some()) && ImageInfo::load(image).is_some_and(|info| info.guest_network.is_none()) {
+        return Err(Error::Invalid(format!("{} was generated without the guest network script, generate it again", image)));
+    }
+    let instance = match &learned {
+        Some(_) if instance.slot > 0 => {
+            return Err(Error::Invalid("A learned network has fixed addresses, run one emulation at a time with it".to_string()));
         }
+        Some(plan) => Instance { host_ip: plan.host_ip.clone(), guest_ip: plan.guest_ip.clone(), ..instance.clone() },
+        None => instance.clone(),
+    };
+    let mut profile = MachineProfile::load(&profile_name)?;
+    profile.apply(Assets::get()?, overrides);
+    let MachineProfile { qemu, kernel, machine, drive_if, root_device, net_device, memory, append, extra_args } = &profile;
+
+    if !std::path::Path::new(kernel).exists() {
+        return Err(Error::Invalid(format!("Kernel {} not found, build it with {} for {}",
+            kernel, Assets::get()?.kernel_build_script().display(), profile_name)));
+    }
+    let config = Config::get()?;
+    let qemu = config.qemu_binary(qemu);
+    let memory = memory.as_deref().unwrap_or(&config.memory);
+    free_socket(&instance.qmp)?;
+    let _network = init_network(options.network, &instance)?;
+
+    let overlay = match options.overlay {
+        Overlay::Off => None,
+        mode => Some((overlay_path(image, &instance.tap), mode)),
+    };
+    let _overlay = match &overlay {
+        Some((path, mode)) => overlay::create_overlay(image, path, *mode)?,
+        None => None,
+    };
+    let drive = overlay.as_ref().map_or(image, |(path, _)| path.as_str());
+    let image_format = ImageType::detect(drive).to_str().to_string();
+    let mut guest_env = vec![format!("FAE_GUEST_IP={}", instance.guest_ip)];
+    match &learned {
+        Some(plan) => guest_env.extend(plan.guest_env()),
+        None => guest_env.push(format!("FAE_NETWORK={}", options.network.to_str())),
+    }
+    if let Some(learn) = options.learn {
+        guest_env.push(format!("FAE_LEARN={}", learn.as_secs()));
     }
 
     let mut sudo = Command::new("sudo");
     let process  = sudo
-        .args(&[
+        .args([
             qemu,
             "-kernel", kernel,
             "-M", machine,
-            "-drive", &format!("if={drive_if},format=qcow2,file={image},id=rootfs"), // "-hda", image,
-            "-m", "256M",
+            "-drive", &format!("if={drive_if},format={image_format},file={drive},id=rootfs"), // "-hda", image,
+            "-m", memory,
             "-nographic",
-            "-append", &format!("root={root_device} rw init=preInit.sh"), // sda1 /dev/mmcblk0
-            "-netdev", "tap,id=net0,ifname=tap-qemu,script=no,downscript=no",
-            "-device", &format!("{net_device},netdev=net0,id=nic1")
+            // unknown key=value parameters reach preInit.sh as environment
+            "-append", format!("root={root_device} rw init=preInit.sh {} {}",
+                guest_env.join(" "), append.as_deref().unwrap_or("")).trim_end(), // sda1 /dev/mmcblk0
+            "-netdev", &format!("tap,id=net0,ifname={},script=no,downscript=no", instance.tap),
+            "-device", &format!("{net_device},netdev=net0,id=nic1"),
+            "-qmp", &format!("unix:{},server=on,wait=off", instance.qmp.display()),
         ]);
-    if *debug {
-        process.args(&["-s", "-S"]);
+    if options.debug {
+        process.args(["-s", "-S"]);
+    }
+    if let Some(name) = &options.loadvm {
+        process.args(["-loadvm", name]);
+    }
+    process.args(extra_args);
+
+    let headless = matches!(instance.console, Console::Headless { .. });
+    // a restored guest prints no boot to watch
+    let monitored = options.loadvm.is_none()
+        && (options.boot_timeout.is_some() || options.savevm.is_some() || options.learn.is_some());
+    let log = match &instance.console {
+        Console::Headless { log, .. } => {
+            process.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+            Some(console::create_log(log)?)
+        }
+        Console::Interactive => {
+            process.stdin(Stdio::inherit()) // 允许向 QEMU 发送输入
+                // 捕获 QEMU 的输出，监视启动时经由本进程转发
+                .stdout(if monitored { Stdio::piped() } else { Stdio::inherit() })
+                .stderr(Stdio::inherit()); // 捕获 QEMU 的错误输出
+            None
+        }
+    };
+    let mut process = process.spawn()
+        .map_err(|err| Error::io(format!("Failed to execute command {}", qemu), err))?;
+
+    let (events, monitor) = match monitored {
+        true => {
+            let (sender, receiver) = mpsc::channel();
+            (Some(sender), Some(receiver))
+        }
+        false => (None, None),
+    };
+    let mut loggers = Vec::new();
+    if let Console::Headless { log: path, .. } = &instance.console {
+        println!("Serial console of {} is logged to {}", image, path.display());
+    }
+    if let Some(stdout) = process.stdout.take() {
+        let tee = match &instance.console {
+            Console::Headless { tee, .. } => *tee,
+            Console::Interactive => true,
+        };
+        loggers = console::capture(stdout, process.stderr.take(), log, tee, events);
+    }
+
+    let stop_at_verdict = headless || options.savevm.is_some() || options.learn.is_some();
+    let waited = wait_qemu(&mut process, &instance.qmp, options, monitor.map(|events| (events, stop_at_verdict)));
+    let _ = std::fs::remove_file(&instance.qmp);
+    // QEMU is gone, the loggers drain what is left and stop
+    for logger in loggers {
+        let _ = logger.join();
+    }
+    let (status, verdict) = waited?;
+    match status {
+        Some(status) if !status.success() && verdict.is_none() => {
+            return Err(Error::CommandFailed { command: qemu.to_string(), code: status.code(), stderr: String::new() });
+        }
+        Some(_) => {}
+        None => println!("Stopped emulation of {}", image),
+    }
+    if let Some((path, Overlay::Keep)) = &overlay {
+        println!("Kept overlay {}", path);
+    }
+    if let (Some(name), Some(true)) = (&options.savevm, verdict.as_ref().map(BootVerdict::is_booted)) {
+        let mut info = ImageInfo::load(image).unwrap_or_default();
+        info.arch = info.arch.or(arch.clone());
+        info.snapshots.insert(name.clone(), profile_name);
+        info.save(image)?;
+        println!("Saved snapshot {} of {}", name, image);
+    }
+    Ok(verdict)
+}
+
+/// How long a boot to snapshot may take when no boot timeout is given, on
+/// top of the wait of a learning boot
+const READY_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long `savevm` may take, it writes all of the guest memory
+const SAVEVM_TIMEOUT: Duration = Duration::from_secs(600);
+/// How long QEMU gets to exit after `quit` on QMP
+const QUIT_GRACE: Duration = Duration::from_secs(5);
+
+/// Remove the socket a QEMU that is gone left behind, refusing to start
+/// next to one still running
+fn free_socket(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(Error::Invalid(format!("A QEMU is already serving {}, is another emulation using the tap?", path.display())));
     }
-    match arch {
-        Arch::Arm => {
-            process.args(&["-device", "virtio-blk-device,drive=rootfs"]);
+    run(Command::new("sudo").args(["rm", "-f", &path.to_string_lossy()]))?;
+    Ok(())
+}
+
+/// Stop QEMU, by `quit` on QMP so the image is flushed, else through sudo
+/// which passes SIGTERM on. SIGKILL would leave QEMU behind.
+fn stop_qemu(process: &mut Child, qmp: &Path) -> Result<()> {
+    if Qmp::connect(qmp, Duration::from_secs(1)).and_then(|mut qmp| qmp.quit()).is_ok() {
+        let deadline = Instant::now() + QUIT_GRACE;
+        while Instant::now() < deadline {
+            if process.try_wait().map_err(|err| Error::io("QEMU process wasn't running", err))?.is_some() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100));
         }
-        _ => {}
     }
+    let _ = Command::new("kill").args(["-TERM", &process.id().to_string()]).status();
+    process.wait().map_err(|err| Error::io("QEMU process wasn't running", err))?;
+    Ok(())
+}
+
+/// Save the running guest as `name` through QMP
+fn save_snapshot(qmp: &Path, name: &str) -> Result<()> {
+    let mut client = Qmp::connect(qmp, Duration::from_secs(5))?;
+    client.set_timeout(SAVEVM_TIMEOUT)?;
+    client.save_snapshot(name)
+}
 
-    let mut process = process.stdin(Stdio::inherit()) // 允许向 QEMU 发送输入
-        .stdout(Stdio::inherit()) // 捕获 QEMU 的输出
-        .stderr(Stdio::inherit()) // 捕获 QEMU 的错误输出
-        .spawn()
-        .expect(&format!("Failed to execute command: {}", qemu));
-    let _ = process.wait().expect("QEMU process wasn't running");
+/// Wait for QEMU to exit, or stop it once the timeout is over, returning its
+/// status or None when it was stopped. `monitor` carries the console events
+/// to judge the boot by, and whether to stop QEMU at the verdict, saving
+/// the snapshot asked for first.
+fn wait_qemu(process: &mut Child, qmp: &Path, options: &RunOptions, monitor: Option<(Receiver<ConsoleEvent>, bool)>)
+             -> Result<(Option<ExitStatus>, Option<BootVerdict>)> {
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let boot_timeout = options.boot_timeout
+        .or(options.savevm.as_ref().map(|_| READY_TIMEOUT))
+        .or(options.learn.map(|learn| learn + READY_TIMEOUT));
+    let boot_deadline = boot_timeout.map(|timeout| Instant::now() + timeout);
+    let mut boot = match options.learn {
+        Some(_) => BootMonitor::until(learn::LEARN_DONE_MARKER),
+        None => BootMonitor::new(),
+    };
+    let mut verdict = None;
+    let mut monitor = monitor;
+    let mut shared = false;
+    loop {
+        // QEMU creates the socket once sudo let it start
+        if !shared && qmp.exists() {
+            shared = true;
+            match qmp::share_socket(qmp) {
+                Ok(()) => println!("QMP socket: {}\r", qmp.display()),
+                Err(err) => eprintln!("{}\r", err),
+            }
+        }
+        match &monitor {
+            Some((events, _)) if verdict.is_none() => match events.recv_timeout(Duration::from_millis(200)) {
+                Ok(event) => verdict = boot.feed(&event),
+                Err(RecvTimeoutError::Timeout) => {}
+                // the console closed, QEMU is exiting
+                Err(RecvTimeoutError::Disconnected) => std::thread::sleep(Duration::from_millis(50)),
+            },
+            _ => std::thread::sleep(Duration::from_millis(200)),
+        }
+        if verdict.is_none() && monitor.is_some() && boot_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            verdict = Some(BootVerdict::Timeout);
+        }
+        if let Some((_, stop_at_verdict)) = &monitor {
+            if verdict.is_some() {
+                let stop_at_verdict = *stop_at_verdict;
+                // stop listening, the pump keeps teeing and logging
+                monitor = None;
+                if stop_at_verdict {
+                    let saved = match (&options.savevm, &verdict) {
+                        (Some(name), Some(BootVerdict::Booted { .. })) => save_snapshot(qmp, name),
+                        _ => Ok(()),
+                    };
+                    stop_qemu(process, qmp)?;
+                    saved?;
+                    return Ok((None, verdict));
+                }
+                // QEMU goes on with the terminal in raw mode
+                println!("\r\nBoot verdict: {}\r", verdict.as_ref().map_or(String::new(), |verdict| verdict.to_string()));
+            }
+        }
+        if let Some(status) = process.try_wait().map_err(|err| Error::io("QEMU process wasn't running", err))? {
+            if monitor.is_some() && verdict.is_none() {
+                verdict = Some(BootVerdict::Exited { code: status.code() });
+            }
+            return Ok((Some(status), verdict));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            stop_qemu(process, qmp)?;
+            if monitor.is_some() && verdict.is_none() {
+                verdict = Some(BootVerdict::Timeout);
+            }
+            return Ok((None, verdict));
+        }
+    }
 }
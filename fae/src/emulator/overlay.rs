@@ -0,0 +1,74 @@
+//! Throwaway qcow2 overlays backed by a generated image, so a run can't
+//! break the image and several runs can share it.
+
+use std::path::Path;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use super::console::run_stamp;
+use crate::error::{run, Error, Result};
+use crate::guard::{Guard, Resource};
+use crate::utils::ImageType;
+
+/// Where the writes of the guest go
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Overlay {
+    /// into the image itself
+    #[default]
+    Off,
+    /// into an overlay removed after the run
+    Discard,
+    /// into an overlay left next to the image
+    Keep,
+}
+
+/// `<image>.<run stamp>.<tap>.overlay.qcow2`, the tap keeps runs started in
+/// the same second apart
+pub fn overlay_path(image: &str, tap: &str) -> String {
+    format!("{}.{}.{}.overlay.qcow2", image, run_stamp(), tap)
+}
+
+/// Create an overlay at `path` backed by `image`. The guard removes it
+/// again unless it is to be kept.
+pub fn create_overlay(image: &str, path: &str, overlay: Overlay) -> Result<Option<Guard>> {
+    // the backing file is resolved from the overlay's directory otherwise
+    let backing = Path::new(image).canonicalize()
+        .map_err(|err| Error::io(format!("Image {} not found", image), err))?;
+    run(Command::new("qemu-img").args([
+        "create", "-f", "qcow2",
+        "-F", ImageType::detect(image).to_str(),
+        "-b", &backing.to_string_lossy(),
+        path,
+    ]))?;
+    println!("Writes of this run go to overlay {}", path);
+    Ok(match overlay {
+        Overlay::Discard => Some(Guard::new(Resource::File(path.to_string()))),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_path_is_unique_per_tap() {
+        let first = overlay_path("/data/image.qcow2", "tap-qemu");
+        let second = overlay_path("/data/image.qcow2", "tap-qemu1");
+        assert_ne!(first, second);
+        for (path, tap) in [(&first, "tap-qemu"), (&second, "tap-qemu1")] {
+            assert!(path.starts_with("/data/image.qcow2."), "{}", path);
+            assert!(path.ends_with(&format!(".{}.overlay.qcow2", tap)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn overlay_of_a_missing_image_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("missing.qcow2");
+        let path = overlay_path(image.to_str().unwrap(), "tap-qemu");
+        assert!(matches!(create_overlay(image.to_str().unwrap(), &path, Overlay::Discard), Err(Error::Io { .. })));
+        assert!(!Path::new(&path).exists());
+    }
+}
@@ -0,0 +1,195 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::assets::Assets;
+use crate::error::{Error, Result};
+
+/// How to boot one kind of guest in QEMU, read from `<name>.toml`. A
+/// relative kernel path is relative to the assets root.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MachineProfile {
+    /// QEMU system emulator, e.g. `qemu-system-mips`
+    pub qemu: String,
+    pub kernel: String,
+    /// `-M` board
+    pub machine: String,
+    /// `if=` of the rootfs drive
+    pub drive_if: String,
+    /// device the kernel mounts as root, e.g. `/dev/sda1`
+    pub root_device: String,
+    /// NIC model attached to the tap
+    pub net_device: String,
+    /// guest memory, the config's `memory` when unset
+    #[serde(default)]
+    pub memory: Option<String>,
+    /// appended to the kernel command line
+    #[serde(default)]
+    pub append: Option<String>,
+    /// passed to QEMU as is, e.g. devices the board lacks
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+/// Per task changes on top of a profile, every field is optional. A
+/// relative kernel path is relative to the assets root, as in a profile.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProfileOverrides {
+    pub qemu: Option<String>,
+    pub kernel: Option<String>,
+    pub machine: Option<String>,
+    pub drive_if: Option<String>,
+    pub root_device: Option<String>,
+    pub net_device: Option<String>,
+    pub memory: Option<String>,
+    pub append: Option<String>,
+    pub extra_args: Option<Vec<String>>,
+}
+
+impl MachineProfile {
+    /// Load a profile by name from the `profiles` directory of the assets,
+    /// or from a path when `profile` ends in `.toml`
+    pub fn load(profile: &str) -> Result<Self> {
+        Self::load_in(Assets::get()?, profile)
+    }
+
+    pub fn load_in(assets: &Assets, profile: &str) -> Result<Self> {
+        let path = if profile.ends_with(".toml") {
+            Path::new(profile).to_path_buf()
+        } else {
+            assets.profiles().join(format!("{}.toml", profile))
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| Error::io(format!("Failed to read machine profile {}", path.display()), err))?;
+        let mut profile: Self = toml::de::from_str(&content).map_err(|err| Error::parse(path.display().to_string(), err))?;
+        profile.kernel = assets.resolve_path(&profile.kernel);
+        Ok(profile)
+    }
+
+    /// Replace the fields `overrides` sets, `extra_args` as a whole
+    pub fn apply(&mut self, assets: &Assets, overrides: &ProfileOverrides) {
+        let ProfileOverrides { qemu, kernel, machine, drive_if, root_device, net_device, memory, append, extra_args } =
+            overrides.clone();
+        let kernel = kernel.map(|kernel| assets.resolve_path(&kernel));
+        let fields = [
+            (&mut self.qemu, qemu),
+            (&mut self.kernel, kernel),
+            (&mut self.machine, machine),
+            (&mut self.drive_if, drive_if),
+            (&mut self.root_device, root_device),
+            (&mut self.net_device, net_device),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        if memory.is_some() {
+            self.memory = memory;
+        }
+        if append.is_some() {
+            self.append = append;
+        }
+        if let Some(extra_args) = extra_args {
+            self.extra_args = extra_args;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn shipped() -> Assets {
+        Assets { root: Path::new(env!("CARGO_MANIFEST_DIR")).join(".."), origin: "the source tree".to_string() }
+    }
+
+    fn assets() -> Assets {
+        Assets { root: PathBuf::from("/assets"), origin: "test".to_string() }
+    }
+
+    fn profile() -> MachineProfile {
+        MachineProfile {
+            qemu: "qemu-system-mipsel".to_string(),
+            kernel: "/assets/binaries/kernel/vmlinux.mipsel.4".to_string(),
+            machine: "malta".to_string(),
+            drive_if: "ide".to_string(),
+            root_device: "/dev/sda1".to_string(),
+            net_device: "e1000".to_string(),
+            memory: Some("256M".to_string()),
+            append: Some("console=ttyS0".to_string()),
+            extra_args: vec!["-device".to_string(), "e1000,netdev=net1".to_string()],
+        }
+    }
+
+    #[test]
+    fn apply_replaces_only_what_is_set() {
+        let mut applied = profile();
+        applied.apply(&assets(), &ProfileOverrides::default());
+        assert_eq!(format!("{:?}", applied), format!("{:?}", profile()));
+
+        applied.apply(&assets(), &ProfileOverrides {
+            machine: Some("malta-custom".to_string()),
+            memory: Some("1G".to_string()),
+            extra_args: Some(vec!["-no-reboot".to_string()]),
+            ..ProfileOverrides::default()
+        });
+        assert_eq!(applied.machine, "malta-custom");
+        assert_eq!(applied.memory.as_deref(), Some("1G"));
+        // replaced, not appended to
+        assert_eq!(applied.extra_args, ["-no-reboot"]);
+        assert_eq!(applied.qemu, "qemu-system-mipsel");
+        assert_eq!(applied.kernel, "/assets/binaries/kernel/vmlinux.mipsel.4");
+        assert_eq!(applied.append.as_deref(), Some("console=ttyS0"));
+        assert_eq!(applied.net_device, "e1000");
+
+        applied.apply(&assets(), &ProfileOverrides { extra_args: Some(Vec::new()), ..ProfileOverrides::default() });
+        assert!(applied.extra_args.is_empty());
+    }
+
+    #[test]
+    fn overridden_kernel_is_resolved_like_a_profile_one() {
+        let mut applied = profile();
+        applied.apply(&assets(), &ProfileOverrides {
+            kernel: Some("binaries/kernel/vmlinux.mipsel.4.custom".to_string()),
+            ..ProfileOverrides::default()
+        });
+        assert_eq!(applied.kernel, "/assets/binaries/kernel/vmlinux.mipsel.4.custom");
+
+        applied.apply(&assets(), &ProfileOverrides { kernel: Some("/boot/vmlinux".to_string()), ..ProfileOverrides::default() });
+        assert_eq!(applied.kernel, "/boot/vmlinux");
+    }
+
+    #[test]
+    fn shipped_profiles_load() {
+        let assets = shipped();
+        let mut names: Vec<String> = std::fs::read_dir(assets.profiles()).unwrap()
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(".toml").map(str::to_string))
+            .collect();
+        names.sort();
+        assert!(names.len() >= 3, "profiles found: {:?}", names);
+        for name in names {
+            let profile = MachineProfile::load_in(&assets, &name).unwrap_or_else(|err| panic!("{}: {}", name, err));
+            assert!(Path::new(&profile.kernel).starts_with(&assets.root), "{}: {}", name, profile.kernel);
+            assert!(profile.qemu.starts_with("qemu-system-"), "{}: {}", name, profile.qemu);
+        }
+    }
+
+    #[test]
+    fn profile_by_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("board.toml");
+        std::fs::write(&path, concat!(
+            "qemu = \"qemu-system-arm\"\nkernel = \"zImage\"\nmachine = \"virt\"\n",
+            "drive_if = \"none\"\nroot_device = \"/dev/vda1\"\nnet_device = \"virtio-net-device\"\n",
+        )).unwrap();
+        let profile = MachineProfile::load_in(&shipped(), path.to_str().unwrap()).unwrap();
+        assert_eq!(profile.machine, "virt");
+        assert!(profile.extra_args.is_empty());
+
+        std::fs::write(&path, "qemu = \"qemu-system-arm\"\n").unwrap();
+        assert!(matches!(MachineProfile::load_in(&shipped(), path.to_str().unwrap()), Err(Error::Parse { .. })));
+    }
+}
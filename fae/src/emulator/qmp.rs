@@ -0,0 +1,371 @@
+//! Client of the QMP socket every emulation is started with, to drive a
+//! running guest from code: status, pause and resume, snapshots, devices
+//! and power off.
+//!
+//! QEMU serves one client at a time, so connect, do what is needed and drop
+//! the client again.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::error::{run, Error, Result};
+
+/// How long a command may take, `savevm` of a large guest included
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Socket of the QEMU running on `tap`, which is unique among the guests
+/// running at the same time
+pub fn qmp_socket(tap: &str) -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    dir.join(format!("fae-{}.qmp", tap))
+}
+
+/// QEMU runs as root, hand the socket it created to the user running us so
+/// clients don't need sudo
+pub(crate) fn share_socket(path: &Path) -> Result<()> {
+    let id = |flag: &str| run(Command::new("id").arg(flag))
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    let owner = format!("{}:{}", id("-u")?, id("-g")?);
+    run(Command::new("sudo").args(["chown", &owner, &path.to_string_lossy()]))?;
+    Ok(())
+}
+
+/// `query-status`
+#[derive(Debug, Clone, Deserialize)]
+pub struct VmStatus {
+    pub running: bool,
+    /// e.g. `running`, `paused`, `shutdown`
+    pub status: String,
+}
+
+/// A block device of `query-block`
+#[derive(Debug, Clone)]
+pub struct BlockDevice {
+    pub device: String,
+    /// image backing the device, None when no medium is inserted
+    pub file: Option<String>,
+    pub format: Option<String>,
+    pub read_only: bool,
+}
+
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    /// asynchronous events read while waiting for replies
+    events: Vec<Value>,
+}
+
+impl Qmp {
+    /// Connect to `path`, retrying until `timeout` while QEMU is starting,
+    /// and leave capabilities negotiation behind
+    pub fn connect(path: &Path, timeout: Duration) -> Result<Self> {
+        let deadline = Instant::now() + timeout;
+        let stream = loop {
+            match UnixStream::connect(path) {
+                Ok(stream) => break stream,
+                Err(err) if Instant::now() >= deadline => {
+                    return Err(Error::io(format!("Failed to connect to QMP socket {}", path.display()), err));
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        stream.set_read_timeout(Some(DEFAULT_TIMEOUT))
+            .map_err(|err| Error::io("Failed to set QMP timeout", err))?;
+        let writer = stream.try_clone().map_err(|err| Error::io("Failed to clone QMP socket", err))?;
+        let mut qmp = Qmp { reader: BufReader::new(stream), writer, events: Vec::new() };
+
+        let greeting = qmp.read()?;
+        if greeting.get("QMP").is_none() {
+            return Err(Error::parse("QMP greeting", greeting));
+        }
+        qmp.execute("qmp_capabilities", None)?;
+        Ok(qmp)
+    }
+
+    /// How long to wait for a reply
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.reader.get_ref().set_read_timeout(Some(timeout)).map_err(|err| Error::io("Failed to set QMP timeout", err))
+    }
+
+    fn read(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        let read = self.reader.read_line(&mut line).map_err(|err| Error::io("Failed to read from QMP", err))?;
+        if read == 0 {
+            return Err(Error::Invalid("QMP socket closed, QEMU is gone".to_string()));
+        }
+        serde_json::from_str(&line).map_err(|err| Error::parse("QMP message", err))
+    }
+
+    /// Run `command` and return what it returned. Events arriving in the
+    /// meantime are kept for [`Qmp::events`].
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        writeln!(self.writer, "{}", request).map_err(|err| Error::io(format!("Failed to send {} to QMP", command), err))?;
+        loop {
+            let mut message = self.read()?;
+            if message.get("event").is_some() {
+                self.events.push(message);
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                let desc = error.get("desc").and_then(Value::as_str).unwrap_or("unknown error");
+                return Err(Error::Invalid(format!("QMP {} failed: {}", command, desc)));
+            }
+            return Ok(message.get_mut("return").map(Value::take).unwrap_or(Value::Null));
+        }
+    }
+
+    /// Events received so far, e.g. `STOP`, `RESUME` or `SHUTDOWN`
+    pub fn events(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// A monitor command as typed at the `(qemu)` prompt, returning its output
+    pub fn human(&mut self, command_line: &str) -> Result<String> {
+        let output = self.execute("human-monitor-command", Some(json!({ "command-line": command_line })))?;
+        Ok(output.as_str().unwrap_or_default().to_string())
+    }
+
+    /// A monitor command that prints nothing unless it failed
+    fn human_quiet(&mut self, command_line: &str) -> Result<()> {
+        let output = self.human(command_line)?;
+        match output.trim() {
+            "" => Ok(()),
+            output => Err(Error::Invalid(format!("`{}` failed: {}", command_line, output))),
+        }
+    }
+
+    pub fn status(&mut self) -> Result<VmStatus> {
+        let status = self.execute("query-status", None)?;
+        serde_json::from_value(status).map_err(|err| Error::parse("query-status", err))
+    }
+
+    pub fn pause(&mut self) -> Result<()> {
+        self.execute("stop", None).map(|_| ())
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        self.execute("cont", None).map(|_| ())
+    }
+
+    /// Save the whole machine under `name`, in the qcow2 image
+    pub fn save_snapshot(&mut self, name: &str) -> Result<()> {
+        self.human_quiet(&format!("savevm {}", name))
+    }
+
+    pub fn load_snapshot(&mut self, name: &str) -> Result<()> {
+        self.human_quiet(&format!("loadvm {}", name))
+    }
+
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        self.human_quiet(&format!("delvm {}", name))
+    }
+
+    /// The table of `info snapshots`
+    pub fn snapshots(&mut self) -> Result<String> {
+        self.human("info snapshots")
+    }
+
+    pub fn block_devices(&mut self) -> Result<Vec<BlockDevice>> {
+        let devices = self.execute("query-block", None)?;
+        let devices = devices.as_array().ok_or_else(|| Error::parse("query-block", &devices))?;
+        Ok(devices.iter().map(|device| {
+            let inserted = device.get("inserted");
+            let field = |name: &str| inserted.and_then(|inserted| inserted.get(name)).and_then(Value::as_str).map(str::to_string);
+            BlockDevice {
+                device: device.get("device").and_then(Value::as_str).unwrap_or_default().to_string(),
+                file: field("file"),
+                format: field("drv"),
+                read_only: inserted.and_then(|inserted| inserted.get("ro")).and_then(Value::as_bool).unwrap_or(false),
+            }
+        }).collect())
+    }
+
+    /// The NICs and their backends as `info network` shows them, QMP has
+    /// no query for them
+    pub fn network(&mut self) -> Result<String> {
+        self.human("info network")
+    }
+
+    /// Ask the guest to power off, as pressing the power button would
+    pub fn powerdown(&mut self) -> Result<()> {
+        self.execute("system_powerdown", None).map(|_| ())
+    }
+
+    /// Stop QEMU right away, flushing the image
+    pub fn quit(&mut self) -> Result<()> {
+        match self.execute("quit", None) {
+            // QEMU may close the socket before its reply gets out
+            Err(Error::Io { .. }) => Ok(()),
+            Err(Error::Invalid(message)) if message.starts_with("QMP socket closed") => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread::JoinHandle;
+
+    const GREETING: &str = r#"{"QMP": {"version": {"qemu": {"micro": 0, "minor": 2, "major": 8}}, "capabilities": ["oob"]}}"#;
+
+    /// A QMP server for one client: it sends `greeting`, then answers each
+    /// request with the lines of the next step, or closes the connection
+    /// when they run out. Returns the requests it received.
+    fn serve(greeting: &str, steps: Vec<Vec<&str>>) -> (tempfile::TempDir, PathBuf, JoinHandle<Vec<Value>>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fae-test.qmp");
+        let listener = UnixListener::bind(&path).unwrap();
+        let greeting = greeting.to_string();
+        let steps: Vec<Vec<String>> = steps.into_iter()
+            .map(|lines| lines.into_iter().map(str::to_string).collect())
+            .collect();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writeln!(writer, "{}", greeting).unwrap();
+            let mut requests = Vec::new();
+            for lines in steps {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                requests.push(serde_json::from_str(&line).unwrap());
+                for line in lines {
+                    writeln!(writer, "{}", line).unwrap();
+                }
+            }
+            requests
+        });
+        (dir, path, server)
+    }
+
+    const OK: &str = r#"{"return": {}}"#;
+
+    fn connect(path: &Path) -> Result<Qmp> {
+        Qmp::connect(path, Duration::from_secs(5))
+    }
+
+    #[test]
+    fn connect_negotiates_capabilities() {
+        let (_dir, path, server) = serve(GREETING, vec![
+            vec![OK],
+            vec![r#"{"return": {"running": true, "singlestep": false, "status": "running"}}"#],
+        ]);
+        let mut qmp = connect(&path).unwrap();
+        let status = qmp.status().unwrap();
+        assert!(status.running);
+        assert_eq!(status.status, "running");
+        drop(qmp);
+        assert_eq!(server.join().unwrap(), vec![
+            json!({ "execute": "qmp_capabilities" }),
+            json!({ "execute": "query-status" }),
+        ]);
+    }
+
+    #[test]
+    fn greeting_is_checked() {
+        let (_dir, path, server) = serve(r#"{"hello": "not qemu"}"#, vec![]);
+        assert!(matches!(connect(&path), Err(Error::Parse { .. })));
+        server.join().unwrap();
+
+        let (_dir, path, server) = serve("not json", vec![]);
+        assert!(matches!(connect(&path), Err(Error::Parse { .. })));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn events_before_a_reply_are_kept() {
+        let (_dir, path, server) = serve(GREETING, vec![
+            vec![OK],
+            vec![
+                r#"{"timestamp": {"seconds": 1, "microseconds": 2}, "event": "STOP"}"#,
+                r#"{"timestamp": {"seconds": 1, "microseconds": 3}, "event": "NIC_RX_FILTER_CHANGED", "data": {"path": "/machine/peripheral/nic1"}}"#,
+                OK,
+            ],
+        ]);
+        let mut qmp = connect(&path).unwrap();
+        qmp.pause().unwrap();
+        let events: Vec<&str> = qmp.events.iter().filter_map(|event| event["event"].as_str()).collect();
+        assert_eq!(events, ["STOP", "NIC_RX_FILTER_CHANGED"]);
+        assert_eq!(qmp.events().len(), 2);
+        assert!(qmp.events().is_empty());
+        drop(qmp);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn errors_are_invalid() {
+        let (_dir, path, server) = serve(GREETING, vec![
+            vec![OK],
+            vec![r#"{"error": {"class": "GenericError", "desc": "Device 'nic9' not found"}}"#],
+            vec![r#"{"error": {"class": "GenericError"}}"#],
+        ]);
+        let mut qmp = connect(&path).unwrap();
+        match qmp.execute("device_del", Some(json!({ "id": "nic9" }))) {
+            Err(Error::Invalid(message)) => assert_eq!(message, "QMP device_del failed: Device 'nic9' not found"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(qmp.resume(), Err(Error::Invalid(message)) if message.ends_with("unknown error")));
+        drop(qmp);
+        assert_eq!(server.join().unwrap()[1], json!({ "execute": "device_del", "arguments": { "id": "nic9" } }));
+    }
+
+    #[test]
+    fn human_output_fails_quiet_commands() {
+        let (_dir, path, server) = serve(GREETING, vec![
+            vec![OK],
+            vec![r#"{"return": ""}"#],
+            vec![r#"{"return": "Error: Device 'virtio0' is writable but does not support snapshots\r\n"}"#],
+            vec![r#"{"return": "\r\n"}"#],
+        ]);
+        let mut qmp = connect(&path).unwrap();
+        qmp.save_snapshot("booted").unwrap();
+        match qmp.load_snapshot("booted") {
+            Err(Error::Invalid(message)) => {
+                assert_eq!(message, "`loadvm booted` failed: Error: Device 'virtio0' is writable but does not support snapshots");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        qmp.delete_snapshot("booted").unwrap();
+        drop(qmp);
+        let requests = server.join().unwrap();
+        assert_eq!(requests[1], json!({ "execute": "human-monitor-command", "arguments": { "command-line": "savevm booted" } }));
+        assert_eq!(requests[3]["arguments"]["command-line"], "delvm booted");
+    }
+
+    #[test]
+    fn quit_is_fine_when_the_peer_closes() {
+        // the server has no step for `quit`, it hangs up on reading it
+        let (_dir, path, server) = serve(GREETING, vec![vec![OK], vec![]]);
+        let mut qmp = connect(&path).unwrap();
+        qmp.quit().unwrap();
+        server.join().unwrap();
+
+        let (_dir, path, server) = serve(GREETING, vec![vec![OK], vec![OK]]);
+        let mut qmp = connect(&path).unwrap();
+        qmp.quit().unwrap();
+        drop(qmp);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn closed_socket_fails_other_commands() {
+        let (_dir, path, server) = serve(GREETING, vec![vec![OK], vec![]]);
+        let mut qmp = connect(&path).unwrap();
+        assert!(qmp.powerdown().is_err());
+        server.join().unwrap();
+    }
+}
@@ -1,43 +1,207 @@
-pub fn init_network() {
-    let tap_name = "tap-qemu";
-    let ip_addr = "192.168.1.1/24";
-    // Create tap
-    let output = std::process::Command::new("sudo")
-        .args(&["bash", "-c"])
-        .arg(&format!("ip tuntap add {} mode tap", tap_name))
-        .output()
-        .expect("Failed to execute command: ip tuntap");
-
-    if !output.status.success() {
-        eprintln!("tuntap: {} already exist", tap_name);
-    } else {
-        println!("Create tuntap: {}", tap_name)
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use super::console::Console;
+use super::qmp::qmp_socket;
+use crate::config::Config;
+use crate::error::{run, Error, Result};
+use crate::guard::{Guard, Resource};
+
+/// Longest interface name Linux accepts
+const IFNAMSIZ: usize = 15;
+
+/// How the guest network reaches the host
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NetworkMode {
+    /// the host address sits on the tap itself
+    #[default]
+    Tap,
+    /// the tap joins a bridge holding the host address, along with the
+    /// uplink of the config if there is one
+    HostBridge,
+    /// the host address sits on the tap, the guest network script of the
+    /// image bridges eth0 inside the guest, as FirmAE does
+    GuestBridge,
+    /// the addresses and bridge a learning boot found the firmware to use,
+    /// on the tap
+    Learned,
+}
+
+impl NetworkMode {
+    /// As passed to preInit.sh in `FAE_NETWORK`
+    pub fn to_str(&self) -> &str {
+        match self {
+            NetworkMode::Tap => "tap",
+            NetworkMode::HostBridge => "host-bridge",
+            NetworkMode::GuestBridge => "guest-bridge",
+            NetworkMode::Learned => "learned",
+        }
     }
+}
 
-    // Set ip address for tap
-    let output = std::process::Command::new("sudo")
-        .args(&["bash", "-c"])
-        .arg(&format!("ip add add {} dev {}", ip_addr, tap_name))
-        .output()
-        .expect("Failed to execute command: ip addr");
+/// Host side of one QEMU instance. Instances running at the same time use
+/// different slots, so they get their own tap, bridge and subnet.
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub slot: usize,
+    pub tap: String,
+    pub bridge: String,
+    /// physical interface for the bridge, a task file runs host-bridge
+    /// targets one at a time when there is one
+    pub uplink: Option<String>,
+    /// host address on the tap, with prefix length
+    pub host_ip: String,
+    /// address preInit.sh gives eth0, with prefix length
+    pub guest_ip: String,
+    pub console: Console,
+    /// QMP socket of the QEMU, one per tap
+    pub qmp: PathBuf,
+}
 
-    if !output.status.success() {
-        eprintln!("Ip addr {} already exist for {}", ip_addr, tap_name);
-    } else {
-        println!("Set ip address for {}", tap_name);
+impl Instance {
+    /// Slot 0 is the tap, bridge and address of the config. Slot `n` appends
+    /// `n` to the interface names and moves the address `n` subnets up, e.g.
+    /// 192.168.1.1/24 becomes 192.168.3.1/24 for slot 2. The guest takes the
+    /// next address.
+    pub fn slot(config: &Config, slot: usize) -> Result<Self> {
+        let invalid = || Error::Invalid(format!("host_ip {} is not an IPv4 address with prefix length", config.host_ip));
+        let (address, prefix) = config.host_ip.split_once('/').ok_or_else(invalid)?;
+        let address: Ipv4Addr = address.parse().map_err(|_| invalid())?;
+        let prefix: u32 = prefix.parse().ok().filter(|prefix| *prefix <= 30).ok_or_else(invalid)?;
+
+        let host = u32::from(address)
+            .checked_add((slot as u32).saturating_mul(1 << (32 - prefix)))
+            .ok_or_else(|| Error::Invalid(format!("No subnet left for slot {} after {}", slot, config.host_ip)))?;
+        let interface = |name: &str| {
+            let name = match slot {
+                0 => name.to_string(),
+                slot => format!("{}{}", name, slot),
+            };
+            if name.len() > IFNAMSIZ {
+                return Err(Error::Invalid(format!("Interface name {} is longer than {} characters", name, IFNAMSIZ)));
+            }
+            Ok(name)
+        };
+        let tap = interface(&config.tap)?;
+        Ok(Instance {
+            slot,
+            bridge: interface(&config.bridge)?,
+            uplink: config.uplink.clone(),
+            host_ip: format!("{}/{}", Ipv4Addr::from(host), prefix),
+            guest_ip: format!("{}/{}", Ipv4Addr::from(host + 1), prefix),
+            console: Console::Interactive,
+            qmp: qmp_socket(&tap),
+            tap,
+        })
     }
+}
 
-    // Set tap up
-    let output = std::process::Command::new("sudo")
-        .args(&["bash", "-c"])
-        .arg(&format!("ip link set {} up", tap_name))
-        .output()
-        .expect("Failed to execute command: ip link");
+/// Create the tap, the guard is only returned when it was created here,
+/// one that already existed is left in place
+fn create_tap(tap_name: &str) -> Result<Option<Guard>> {
+    match run(Command::new("sudo").args(["ip", "tuntap", "add", tap_name, "mode", "tap"])) {
+        Ok(_) => {
+            println!("Create tuntap: {}", tap_name);
+            Ok(Some(Guard::new(Resource::Tap(tap_name.to_string()))))
+        }
+        Err(Error::CommandFailed { .. }) => {
+            eprintln!("tuntap: {} already exist", tap_name);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Put `ip_addr` on `device`, an address already there is fine
+fn add_address(device: &str, ip_addr: &str) -> Result<()> {
+    match run(Command::new("sudo").args(["ip", "addr", "add", ip_addr, "dev", device])) {
+        Ok(_) => println!("Set ip address for {}", device),
+        Err(Error::CommandFailed { .. }) => eprintln!("Ip addr {} already exist for {}", ip_addr, device),
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
 
-    if !output.status.success() {
-        eprintln!("Failed to set set eth0 up: {}", String::from_utf8_lossy(&output.stderr));
-        std::process::exit(1);
+/// Set up the network of `mode` for `instance`. The guards undo what was
+/// set up here, newest first as they are dropped.
+pub fn init_network(mode: NetworkMode, instance: &Instance) -> Result<Vec<Guard>> {
+    match mode {
+        NetworkMode::Tap | NetworkMode::GuestBridge | NetworkMode::Learned => Ok(init_tap(&instance.tap, &instance.host_ip)?.into_iter().collect()),
+        NetworkMode::HostBridge => init_host_bridge(instance),
+    }
+}
+
+/// Bring up the tap interface the guest network is attached to, with
+/// `ip_addr` on the host side
+pub fn init_tap(tap_name: &str, ip_addr: &str) -> Result<Option<Guard>> {
+    let tap = create_tap(tap_name)?;
+    add_address(tap_name, ip_addr)?;
+    run(Command::new("sudo").args(["ip", "link", "set", tap_name, "up"]))?;
+    println!("Set {} up", tap_name);
+    Ok(tap)
+}
+
+/// `inet` addresses of `device` and the default gateway through it
+fn uplink_state(device: &str) -> Result<Resource> {
+    let output = run(Command::new("ip").args(["-o", "-4", "addr", "show", "dev", device]))?;
+    let addresses = String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| line.split_whitespace().skip_while(|word| *word != "inet").nth(1).map(str::to_string))
+        .collect();
+    let output = run(Command::new("ip").args(["-4", "route", "show", "default", "dev", device]))?;
+    let gateway = String::from_utf8_lossy(&output.stdout).split_whitespace()
+        .skip_while(|word| *word != "via").nth(1).map(str::to_string);
+    Ok(Resource::Uplink { device: device.to_string(), addresses, gateway })
+}
+
+/// Bridge the tap on the host: the bridge holds the host address and, with
+/// an uplink, takes over the uplink's addresses and default route so the
+/// guest shares its network. Everything is put back when the guards drop.
+fn init_host_bridge(instance: &Instance) -> Result<Vec<Guard>> {
+    let Instance { tap, bridge, uplink, host_ip, .. } = instance;
+    // dropped front to back, so the newest goes in front
+    let mut guards: Vec<Guard> = create_tap(tap)?.into_iter().collect();
+
+    // a bridge that is already there is used as it is, STP included
+    if run(Command::new("ip").args(["link", "show", bridge])).is_ok() {
+        eprintln!("bridge: {} already exist", bridge);
     } else {
-        println!("Set {} up", tap_name);
+        run(Command::new("sudo").args(["ip", "link", "add", "name", bridge, "type", "bridge"]))?;
+        println!("Create bridge: {}", bridge);
+        guards.insert(0, Guard::new(Resource::Bridge(bridge.clone())));
+        // 只有一个网桥，不会有环路
+        run(Command::new("sudo").args(["ip", "link", "set", bridge, "type", "bridge", "stp_state", "0"]))?;
+    }
+    run(Command::new("sudo").args(["ip", "link", "set", tap, "master", bridge]))?;
+    run(Command::new("sudo").args(["ip", "link", "set", tap, "up"]))?;
+
+    let mut moved = Vec::new();
+    let mut gateway = None;
+    if let Some(uplink) = uplink {
+        let state = uplink_state(uplink)?;
+        if let Resource::Uplink { addresses, gateway: uplink_gateway, .. } = &state {
+            moved = addresses.clone();
+            gateway = uplink_gateway.clone();
+        }
+        guards.insert(0, Guard::new(state));
+        for address in &moved {
+            run(Command::new("sudo").args(["ip", "addr", "del", address, "dev", uplink]))?;
+        }
+        run(Command::new("sudo").args(["ip", "link", "set", uplink, "master", bridge]))?;
+        run(Command::new("sudo").args(["ip", "link", "set", uplink, "up"]))?;
+        println!("Add uplink {} to {}", uplink, bridge);
+    }
+
+    add_address(bridge, host_ip)?;
+    for address in &moved {
+        add_address(bridge, address)?;
+    }
+    run(Command::new("sudo").args(["ip", "link", "set", bridge, "up"]))?;
+    if let Some(gateway) = &gateway {
+        run(Command::new("sudo").args(["ip", "route", "replace", "default", "via", gateway, "dev", bridge]))?;
     }
-}
\ No newline at end of file
+    println!("Set {} up", bridge);
+    Ok(guards)
+}
@@ -0,0 +1,83 @@
+//! Errors of every stage, so callers can tell a missing tool from a failed
+//! command or a broken input and decide what to do about it.
+
+use std::process::{Command, Output};
+
+#[derive(Debug)]
+pub enum Error {
+    /// An external tool is not installed
+    MissingTool { tool: String },
+    /// An external command ran and failed
+    CommandFailed { command: String, code: Option<i32>, stderr: String },
+    /// A file or value could not be parsed
+    Parse { what: String, message: String },
+    Io { context: String, source: std::io::Error },
+    /// The request cannot be carried out as given, e.g. no rootfs to build from
+    Invalid(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        Error::Io { context: context.into(), source }
+    }
+
+    pub fn parse(what: impl Into<String>, message: impl std::fmt::Display) -> Self {
+        Error::Parse { what: what.into(), message: message.to_string() }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::MissingTool { tool } => write!(f, "{} is not installed. Please install it and try again.", tool),
+            Error::CommandFailed { command, code, stderr } => {
+                match code {
+                    Some(code) => write!(f, "`{}` failed with exit code {}", command, code)?,
+                    None => write!(f, "`{}` was killed by a signal", command)?,
+                }
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            Error::Parse { what, message } => write!(f, "Failed to parse {}: {}", what, message),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Run `command` to completion. Not finding the program is reported as a
+/// missing tool, for `sudo <tool>` the tool is named instead of sudo.
+pub fn run(command: &mut Command) -> Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let args: Vec<String> = command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+    let rendered = std::iter::once(program.clone()).chain(args.iter().cloned()).collect::<Vec<_>>().join(" ");
+
+    let output = command.output().map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => Error::MissingTool { tool: program.clone() },
+        _ => Error::io(format!("Failed to run `{}`", rendered), err),
+    })?;
+    if output.status.success() {
+        return Ok(output);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    // sudo exits with 1 and says so when the tool itself is missing
+    if program == "sudo" && stderr.contains("command not found") {
+        if let Some(tool) = args.first() {
+            return Err(Error::MissingTool { tool: tool.clone() });
+        }
+    }
+    Err(Error::CommandFailed { command: rendered, code: output.status.code(), stderr })
+}
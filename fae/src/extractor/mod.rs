@@ -0,0 +1,108 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+mod signature;
+mod rootfs;
+pub use signature::{scan, Component, ComponentKind};
+pub use rootfs::{decompress_streams, find_rootfs, unpack_filesystems};
+
+/// What an extraction produced
+#[derive(Debug)]
+pub struct Extraction {
+    /// Directory holding the carved and unpacked components
+    pub directory: PathBuf,
+    pub components: Vec<Component>,
+    /// Most plausible Linux root filesystem among the unpacked directories
+    pub rootfs: Option<PathBuf>,
+}
+
+/// Directory the components of `firmware` are carved into, binwalk style:
+/// `<directory>/_<firmware file name>.extracted`
+pub fn extraction_dir(firmware: &str, directory: &str) -> PathBuf {
+    let file_name = Path::new(firmware)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| firmware.to_string());
+    Path::new(directory).join(format!("_{}.extracted", file_name))
+}
+
+/// Write every component into `output_dir` as `<hex offset>.<extension>`
+pub fn carve_components(data: &[u8], components: &mut [Component], output_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(output_dir)
+        .map_err(|err| Error::io(format!("Failed to create {}", output_dir.display()), err))?;
+    for component in components.iter_mut() {
+        let path = output_dir.join(format!("{:X}.{}", component.offset, component.kind.extension()));
+        let region = &data[component.offset..component.offset + component.size];
+        std::fs::write(&path, region)
+            .map_err(|err| Error::io(format!("Failed to carve {}", path.display()), err))?;
+        component.path = Some(path);
+    }
+    Ok(())
+}
+
+pub fn print_components(components: &[Component]) {
+    println!("{:<16}{:<16}DESCRIPTION", "DECIMAL", "HEXADECIMAL");
+    println!("{}", "-".repeat(80));
+    for component in components {
+        println!(
+            "{:<16}{:<16}{}",
+            component.offset,
+            format!("{:#X}", component.offset),
+            component.description
+        );
+    }
+}
+
+/// Scan `firmware` for known signatures, carve what was found into the
+/// extraction directory under `directory`, unpack the filesystems and
+/// locate the root filesystem among them
+pub fn extract_firmware(firmware: &str, directory: &str) -> Result<Extraction> {
+    let data = std::fs::read(firmware)
+        .map_err(|err| Error::io(format!("Failed to read firmware {}", firmware), err))?;
+
+    let mut components = scan(&data);
+    if components.is_empty() {
+        return Err(Error::Invalid(format!("No known signatures found in {}", firmware)));
+    }
+    print_components(&components);
+
+    let output_dir = extraction_dir(firmware, directory);
+    carve_components(&data, &mut components, &output_dir)?;
+
+    unpack_filesystems(&components, &output_dir);
+    extract_streams(&components, &output_dir, STREAM_NESTING)?;
+    let rootfs = find_rootfs(&output_dir);
+    match &rootfs {
+        Some(rootfs) => println!("Found root filesystem: {}", rootfs.display()),
+        None => eprintln!("No root filesystem found in {}", output_dir.display()),
+    }
+
+    Ok(Extraction { directory: output_dir, components, rootfs })
+}
+
+/// How deep compressed streams inside decompressed streams are followed
+const STREAM_NESTING: u32 = 2;
+
+/// Decompress the streams among `components` and carve and unpack what is
+/// found in each into an extraction directory of its own, binwalk -M style
+fn extract_streams(components: &[Component], output_dir: &Path, depth: u32) -> Result<()> {
+    for stream in decompress_streams(components, output_dir) {
+        let data = std::fs::read(&stream)
+            .map_err(|err| Error::io(format!("Failed to read {}", stream.display()), err))?;
+        let mut nested = scan(&data);
+        if nested.is_empty() {
+            continue;
+        }
+        println!("\n{}:", stream.display());
+        print_components(&nested);
+
+        let nested_dir = extraction_dir(&stream.to_string_lossy(), &output_dir.to_string_lossy());
+        carve_components(&data, &mut nested, &nested_dir)?;
+        unpack_filesystems(&nested, &nested_dir);
+        if depth > 1 {
+            extract_streams(&nested, &nested_dir, depth - 1)?;
+        }
+    }
+    Ok(())
+}
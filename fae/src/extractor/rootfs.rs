@@ -0,0 +1,237 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::signature::{Component, ComponentKind};
+
+/// Unpack carved filesystems into directories next to them, named the way
+/// binwalk does (`squashfs-root`, `squashfs-root-0`, ...).
+/// Returns the directories that were produced.
+pub fn unpack_filesystems(components: &[Component], output_dir: &Path) -> Vec<PathBuf> {
+    let mut unpacked = Vec::new();
+    for component in components {
+        let Some(image) = &component.path else {
+            continue;
+        };
+        let (tool, flag, prefix) = match component.kind {
+            ComponentKind::SquashFs => ("unsquashfs", "-d", "squashfs-root"),
+            ComponentKind::CramFs => ("cramfsck", "-x", "cramfs-root"),
+            ComponentKind::Jffs2 => ("jefferson", "-d", "jffs2-root"),
+            _ => continue,
+        };
+
+        let mut root = output_dir.join(prefix);
+        let mut count = 0;
+        while root.exists() {
+            root = output_dir.join(format!("{}-{}", prefix, count));
+            count += 1;
+        }
+
+        let output = Command::new(tool)
+            .arg(flag)
+            .arg(&root)
+            .arg(image)
+            .output();
+        match output {
+            Ok(output) => {
+                // unprivileged unpacking fails on device nodes but keeps everything else
+                if !output.status.success() {
+                    eprintln!("{} reported errors for {}: {}",
+                        tool, image.display(), String::from_utf8_lossy(&output.stderr).trim());
+                }
+                if root.is_dir() {
+                    println!("Unpacked {} to {}", image.display(), root.display());
+                    unpacked.push(root);
+                }
+            }
+            Err(_) => {
+                eprintln!("{} is not installed, skip unpacking {}", tool, image.display());
+            }
+        }
+    }
+    unpacked
+}
+
+/// Decompress carved gzip, lzma and xz streams next to them, named after
+/// their offset the way binwalk does. Returns the decompressed files.
+pub fn decompress_streams(components: &[Component], output_dir: &Path) -> Vec<PathBuf> {
+    let mut decompressed = Vec::new();
+    for component in components {
+        let Some(stream) = &component.path else {
+            continue;
+        };
+        let (tool, args): (&str, &[&str]) = match component.kind {
+            ComponentKind::Gzip => ("gzip", &["-dc"]),
+            ComponentKind::Lzma => ("xz", &["--format=lzma", "-dc"]),
+            ComponentKind::Xz => ("xz", &["-dc"]),
+            _ => continue,
+        };
+
+        let output = Command::new(tool).args(args).arg(stream).output();
+        match output {
+            Ok(output) => {
+                // a stream is carved up to the next component, the tools
+                // complain about what trails it but still decompress it
+                if !output.status.success() {
+                    eprintln!("{} reported errors for {}: {}",
+                        tool, stream.display(), String::from_utf8_lossy(&output.stderr).trim());
+                }
+                if output.stdout.is_empty() {
+                    continue;
+                }
+                let path = output_dir.join(format!("{:X}", component.offset));
+                match std::fs::write(&path, &output.stdout) {
+                    Ok(()) => {
+                        println!("Decompressed {} to {}", stream.display(), path.display());
+                        decompressed.push(path);
+                    }
+                    Err(err) => eprintln!("Failed to write {}: {}", path.display(), err),
+                }
+            }
+            Err(_) => {
+                eprintln!("{} is not installed, skip decompressing {}", tool, stream.display());
+            }
+        }
+    }
+    decompressed
+}
+
+/// How much a directory looks like the root of a Linux system
+pub fn rootfs_score(dir: &Path) -> u32 {
+    let mut score = 0;
+    for entry in ["bin", "etc", "sbin", "lib", "usr", "dev"] {
+        if dir.join(entry).is_dir() {
+            score += 1;
+        }
+    }
+    // symlinks are not followed on purpose, they point into the guest
+    if dir.join("sbin/init").symlink_metadata().is_ok() {
+        score += 2;
+    }
+    if dir.join("bin/busybox").symlink_metadata().is_ok() {
+        score += 2;
+    }
+    let has_libc = std::fs::read_dir(dir.join("lib"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.file_name().to_string_lossy().starts_with("libc"))
+        })
+        .unwrap_or(false);
+    if has_libc {
+        score += 2;
+    }
+    score
+}
+
+/// Minimum score for a directory to be taken as a root filesystem
+const ROOTFS_THRESHOLD: u32 = 4;
+
+/// Pick the most plausible root filesystem below `directory`
+pub fn find_rootfs(directory: &Path) -> Option<PathBuf> {
+    let mut candidates = Vec::new();
+    collect_dirs(directory, 3, &mut candidates);
+
+    candidates
+        .into_iter()
+        .map(|dir| (rootfs_score(&dir), dir))
+        .filter(|(score, _)| *score >= ROOTFS_THRESHOLD)
+        // prefer the higher score, then the shallower path
+        .max_by(|(a, a_dir), (b, b_dir)| {
+            a.cmp(b).then(b_dir.components().count().cmp(&a_dir.components().count()))
+        })
+        .map(|(_, dir)| dir)
+}
+
+fn collect_dirs(dir: &Path, depth: u32, dirs: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        if is_dir {
+            dirs.push(path.clone());
+            if depth > 1 {
+                collect_dirs(&path, depth - 1, dirs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mkdirs(root: &Path, dirs: &[&str]) {
+        for dir in dirs {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+    }
+
+    /// A root filesystem scoring 12, the most there is
+    fn full_rootfs(root: &Path) {
+        mkdirs(root, &["bin", "etc", "sbin", "lib", "usr", "dev"]);
+        std::fs::write(root.join("bin/busybox"), b"").unwrap();
+        std::fs::write(root.join("lib/libc.so.0"), b"").unwrap();
+        std::os::unix::fs::symlink("/bin/busybox", root.join("sbin/init")).unwrap();
+    }
+
+    #[test]
+    fn score_counts_directories_and_key_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        assert_eq!(rootfs_score(root), 0);
+        mkdirs(root, &["bin", "etc"]);
+        std::fs::write(root.join("usr"), b"not a directory").unwrap();
+        assert_eq!(rootfs_score(root), 2);
+        std::fs::write(root.join("bin/busybox"), b"").unwrap();
+        assert_eq!(rootfs_score(root), 4);
+        mkdirs(root, &["sbin", "lib"]);
+        // a dangling symlink counts, it points into the guest
+        std::os::unix::fs::symlink("/bin/busybox", root.join("sbin/init")).unwrap();
+        std::fs::write(root.join("lib/libcrypto.so"), b"").unwrap();
+        assert_eq!(rootfs_score(root), 10);
+    }
+
+    #[test]
+    fn threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let below = dir.path().join("below");
+        mkdirs(&below, &["bin", "etc", "usr"]);
+        assert_eq!(rootfs_score(&below), ROOTFS_THRESHOLD - 1);
+        assert_eq!(find_rootfs(dir.path()), None);
+
+        let at = dir.path().join("at");
+        mkdirs(&at, &["bin", "etc", "usr", "dev"]);
+        assert_eq!(rootfs_score(&at), ROOTFS_THRESHOLD);
+        assert_eq!(find_rootfs(dir.path()), Some(at));
+    }
+
+    #[test]
+    fn rootfs_nested_at_depth_three() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("_fw.bin.extracted/_40.extracted/squashfs-root");
+        full_rootfs(&rootfs);
+        assert_eq!(find_rootfs(dir.path()), Some(rootfs));
+
+        // one level further is out of reach
+        let deeper = tempfile::tempdir().unwrap();
+        full_rootfs(&deeper.path().join("a/b/c/squashfs-root"));
+        assert_eq!(find_rootfs(deeper.path()), None);
+    }
+
+    #[test]
+    fn higher_score_then_shallower_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let partial = dir.path().join("cramfs-root");
+        mkdirs(&partial, &["bin", "etc", "usr", "dev", "lib"]);
+        let full = dir.path().join("_40.extracted/squashfs-root");
+        full_rootfs(&full);
+        assert_eq!(find_rootfs(dir.path()), Some(full));
+
+        // the same score nearer the top is preferred
+        let shallow = dir.path().join("squashfs-root");
+        full_rootfs(&shallow);
+        assert_eq!(find_rootfs(dir.path()), Some(shallow));
+    }
+}
@@ -0,0 +1,617 @@
+use std::path::PathBuf;
+
+/// Kinds of components the scanner knows how to recognise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComponentKind {
+    UImage,
+    Trx,
+    SquashFs,
+    CramFs,
+    Jffs2,
+    Gzip,
+    Lzma,
+    Xz,
+}
+
+impl ComponentKind {
+    /// Extension used for the carved file, close to what binwalk produces
+    pub fn extension(&self) -> &str {
+        match self {
+            ComponentKind::UImage => "uimage",
+            ComponentKind::Trx => "trx",
+            ComponentKind::SquashFs => "squashfs",
+            ComponentKind::CramFs => "cramfs",
+            ComponentKind::Jffs2 => "jffs2",
+            ComponentKind::Gzip => "gz",
+            ComponentKind::Lzma => "7z",
+            ComponentKind::Xz => "xz",
+        }
+    }
+
+    /// Containers wrap other components, so the scan continues inside them
+    pub fn is_container(&self) -> bool {
+        matches!(self, ComponentKind::UImage | ComponentKind::Trx)
+    }
+}
+
+/// A region of the firmware recognised by its magic signature
+#[derive(Clone, Debug)]
+pub struct Component {
+    pub kind: ComponentKind,
+    pub offset: usize,
+    pub size: usize,
+    /// Human readable summary of the parsed header
+    pub description: String,
+    /// Where the region was carved to, if it was written out
+    pub path: Option<PathBuf>,
+}
+
+/// Result of matching a signature at one offset
+struct Hit {
+    kind: ComponentKind,
+    /// Size declared by the header, if the format has one
+    size: Option<usize>,
+    description: String,
+}
+
+fn be16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn le16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn be32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn le32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn le64(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn be64(data: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+/// Standard (zlib) crc32
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn uimage(data: &[u8]) -> Option<Hit> {
+    if data.len() < 64 || data[..4] != [0x27, 0x05, 0x19, 0x56] {
+        return None;
+    }
+    // header crc is computed with the crc field zeroed
+    let mut header = data[..64].to_vec();
+    header[4..8].fill(0);
+    if crc32(&header) != be32(data, 4) {
+        return None;
+    }
+    let size = be32(data, 12) as usize;
+    let name: String = data[32..64]
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as char)
+        .collect();
+    let compression = match data[31] {
+        0 => "none",
+        1 => "gzip",
+        2 => "bzip2",
+        3 => "lzma",
+        4 => "lzo",
+        5 => "lz4",
+        _ => "unknown",
+    };
+    Some(Hit {
+        kind: ComponentKind::UImage,
+        size: Some(64 + size),
+        description: format!(
+            "uImage header, image name: \"{}\", image size: {} bytes, load address: {:#x}, entry point: {:#x}, compression: {}",
+            name, size, be32(data, 16), be32(data, 20), compression
+        ),
+    })
+}
+
+fn trx(data: &[u8]) -> Option<Hit> {
+    if data.len() < 28 || &data[..4] != b"HDR0" {
+        return None;
+    }
+    let len = le32(data, 4) as usize;
+    let version = le16(data, 14);
+    if !(1..=2).contains(&version) || len < 28 {
+        return None;
+    }
+    let partitions: Vec<String> = (0..3)
+        .map(|i| le32(data, 16 + i * 4))
+        .filter(|&offset| offset != 0)
+        .map(|offset| format!("{:#x}", offset))
+        .collect();
+    Some(Hit {
+        kind: ComponentKind::Trx,
+        size: Some(len),
+        description: format!(
+            "TRX firmware header, version: {}, size: {} bytes, partition offsets: {}",
+            version, len, partitions.join(", ")
+        ),
+    })
+}
+
+fn squashfs(data: &[u8]) -> Option<Hit> {
+    if data.len() < 96 {
+        return None;
+    }
+    let little = match &data[..4] {
+        b"hsqs" => true,
+        b"sqsh" => false,
+        _ => return None,
+    };
+    let u16_at = |at| if little { le16(data, at) } else { be16(data, at) };
+    let u64_at = |at| if little { le64(data, at) } else { be64(data, at) };
+    let major = u16_at(28);
+    let minor = u16_at(30);
+    let bytes_used = match major {
+        4 => Some(u64_at(40)),
+        3 => Some(u64_at(63)),
+        1 | 2 => None,
+        _ => return None,
+    };
+    Some(Hit {
+        kind: ComponentKind::SquashFs,
+        size: bytes_used.map(|size| size as usize),
+        description: format!(
+            "Squashfs filesystem, {} endian, version {}.{}, size: {} bytes",
+            if little { "little" } else { "big" },
+            major,
+            minor,
+            bytes_used.map_or("unknown".to_string(), |size| size.to_string())
+        ),
+    })
+}
+
+fn cramfs(data: &[u8]) -> Option<Hit> {
+    if data.len() < 32 || &data[16..32] != b"Compressed ROMFS" {
+        return None;
+    }
+    let size = match data[..4] {
+        [0x45, 0x3d, 0xcd, 0x28] => le32(data, 4),
+        [0x28, 0xcd, 0x3d, 0x45] => be32(data, 4),
+        _ => return None,
+    };
+    Some(Hit {
+        kind: ComponentKind::CramFs,
+        size: Some(size as usize),
+        description: format!("CramFS filesystem, size: {} bytes", size),
+    })
+}
+
+/// JFFS2 has no superblock, the filesystem is a chain of nodes which we walk
+/// until something that is neither a node nor erased flash shows up
+fn jffs2(data: &[u8]) -> Option<Hit> {
+    let little = match data.get(..2)? {
+        [0x85, 0x19] => true,
+        [0x19, 0x85] => false,
+        _ => return None,
+    };
+    let node_type = |at: usize| if little { le16(data, at) } else { be16(data, at) };
+    let node_len = |at: usize| if little { le32(data, at) } else { be32(data, at) } as usize;
+    let known = |t: u16| matches!(t, 0xe001 | 0xe002 | 0x2003 | 0x2004 | 0x2006 | 0xe008 | 0xe009);
+    if data.len() < 12 || !known(node_type(2)) {
+        return None;
+    }
+
+    let mut pos = 0;
+    let mut nodes = 0;
+    while pos + 12 <= data.len() {
+        if be16(data, pos) == if little { 0x8519 } else { 0x1985 } && known(node_type(pos + 2)) {
+            let len = node_len(pos + 4);
+            if len < 12 {
+                break;
+            }
+            pos += (len + 3) & !3;
+            nodes += 1;
+        } else if le32(data, pos) == 0xffff_ffff || le32(data, pos) == 0 {
+            pos += 4;
+        } else {
+            break;
+        }
+    }
+    Some(Hit {
+        kind: ComponentKind::Jffs2,
+        size: Some(pos.min(data.len())),
+        description: format!(
+            "JFFS2 filesystem, {} endian, {} nodes",
+            if little { "little" } else { "big" },
+            nodes
+        ),
+    })
+}
+
+fn gzip(data: &[u8]) -> Option<Hit> {
+    // magic, deflate method, no reserved flag bits, known OS byte
+    if data.len() < 10 || data[..3] != [0x1f, 0x8b, 0x08] || data[3] & 0xe0 != 0 || (data[9] > 13 && data[9] != 255) {
+        return None;
+    }
+    let mut description = "gzip compressed data".to_string();
+    // FNAME
+    if data[3] & 0x08 != 0 {
+        let name: String = data[10..]
+            .iter()
+            .take(256)
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as char)
+            .collect();
+        description.push_str(&format!(", original file name: \"{}\"", name));
+    }
+    Some(Hit { kind: ComponentKind::Gzip, size: None, description })
+}
+
+fn lzma(data: &[u8]) -> Option<Hit> {
+    if data.len() < 13 || data[0] != 0x5d {
+        return None;
+    }
+    let dictionary = le32(data, 1);
+    let uncompressed = le64(data, 5);
+    if !(1 << 16..=1 << 26).contains(&dictionary) || !dictionary.is_power_of_two() {
+        return None;
+    }
+    if uncompressed != u64::MAX && uncompressed > 1 << 28 {
+        return None;
+    }
+    Some(Hit {
+        kind: ComponentKind::Lzma,
+        size: None,
+        description: format!(
+            "LZMA compressed data, dictionary size: {} bytes, uncompressed size: {}",
+            dictionary,
+            if uncompressed == u64::MAX { "unknown".to_string() } else { format!("{} bytes", uncompressed) }
+        ),
+    })
+}
+
+fn xz(data: &[u8]) -> Option<Hit> {
+    if data.len() < 12 || data[..6] != [0xfd, b'7', b'z', b'X', b'Z', 0x00] {
+        return None;
+    }
+    // stream flags are protected by their own crc
+    if crc32(&data[6..8]) != le32(data, 8) {
+        return None;
+    }
+    Some(Hit {
+        kind: ComponentKind::Xz,
+        size: None,
+        description: "xz compressed data".to_string(),
+    })
+}
+
+fn match_at(data: &[u8]) -> Option<Hit> {
+    match data[0] {
+        0x27 => uimage(data),
+        b'H' => trx(data),
+        b'h' | b's' => squashfs(data),
+        0x45 | 0x28 => cramfs(data),
+        0x85 | 0x19 => jffs2(data),
+        0x1f => gzip(data),
+        0x5d => lzma(data),
+        0xfd => xz(data),
+        _ => None,
+    }
+}
+
+/// Walk the whole blob and return every recognised component, in offset order.
+///
+/// Regions claimed by a filesystem or compressed stream are skipped, so data
+/// inside them does not show up as bogus signatures. Containers (uImage, TRX)
+/// are scanned through, since the interesting payloads live inside them.
+/// Components without a declared size extend up to the next component.
+pub fn scan(data: &[u8]) -> Vec<Component> {
+    let mut components: Vec<Component> = Vec::new();
+    let mut claimed_until = 0;
+    let mut offset = 0;
+
+    while offset < data.len() {
+        if offset < claimed_until {
+            offset = claimed_until;
+            continue;
+        }
+        let Some(hit) = match_at(&data[offset..]) else {
+            offset += 1;
+            continue;
+        };
+
+        let size = hit.size.map(|size| size.min(data.len() - offset));
+        if let Some(size) = size {
+            if !hit.kind.is_container() {
+                claimed_until = offset + size;
+            }
+        } else {
+            // unknown length, nothing else can be claimed inside it
+            claimed_until = offset + 1;
+        }
+        components.push(Component {
+            kind: hit.kind,
+            offset,
+            size: size.unwrap_or(0),
+            description: hit.description,
+            path: None,
+        });
+        offset += 1;
+    }
+
+    // Fill in sizes of streams whose header does not record one
+    let starts: Vec<usize> = components.iter().map(|c| c.offset).collect();
+    for (i, component) in components.iter_mut().enumerate() {
+        if component.size == 0 {
+            let end = starts[i + 1..]
+                .iter()
+                .find(|&&start| start > component.offset)
+                .copied()
+                .unwrap_or(data.len());
+            component.size = end - component.offset;
+        }
+    }
+
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uimage_header(name: &str, size: u32) -> Vec<u8> {
+        let mut header = vec![0u8; 64];
+        header[..4].copy_from_slice(&[0x27, 0x05, 0x19, 0x56]);
+        header[12..16].copy_from_slice(&size.to_be_bytes());
+        header[16..20].copy_from_slice(&0x8000_8000u32.to_be_bytes());
+        header[20..24].copy_from_slice(&0x8000_8040u32.to_be_bytes());
+        header[31] = 3;
+        header[32..32 + name.len()].copy_from_slice(name.as_bytes());
+        let crc = crc32(&header);
+        header[4..8].copy_from_slice(&crc.to_be_bytes());
+        header
+    }
+
+    fn trx_header(len: u32, version: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 28];
+        header[..4].copy_from_slice(b"HDR0");
+        header[4..8].copy_from_slice(&len.to_le_bytes());
+        header[14..16].copy_from_slice(&version.to_le_bytes());
+        header[16..20].copy_from_slice(&28u32.to_le_bytes());
+        header
+    }
+
+    fn squashfs_header(little: bool, major: u16, bytes_used: u64) -> Vec<u8> {
+        let mut header = vec![0u8; 96];
+        header[..4].copy_from_slice(if little { b"hsqs" } else { b"sqsh" });
+        let at = if major == 3 { 63 } else { 40 };
+        if little {
+            header[28..30].copy_from_slice(&major.to_le_bytes());
+            header[at..at + 8].copy_from_slice(&bytes_used.to_le_bytes());
+        } else {
+            header[28..30].copy_from_slice(&major.to_be_bytes());
+            header[at..at + 8].copy_from_slice(&bytes_used.to_be_bytes());
+        }
+        header
+    }
+
+    fn cramfs_header(little: bool, size: u32) -> Vec<u8> {
+        let mut header = vec![0u8; 64];
+        if little {
+            header[..4].copy_from_slice(&[0x45, 0x3d, 0xcd, 0x28]);
+            header[4..8].copy_from_slice(&size.to_le_bytes());
+        } else {
+            header[..4].copy_from_slice(&[0x28, 0xcd, 0x3d, 0x45]);
+            header[4..8].copy_from_slice(&size.to_be_bytes());
+        }
+        header[16..32].copy_from_slice(b"Compressed ROMFS");
+        header
+    }
+
+    /// A little endian JFFS2 node of `len` bytes, padded to 4
+    fn jffs2_node(node_type: u16, len: u32) -> Vec<u8> {
+        let mut node = vec![0u8; (len as usize + 3) & !3];
+        node[..2].copy_from_slice(&[0x85, 0x19]);
+        node[2..4].copy_from_slice(&node_type.to_le_bytes());
+        node[4..8].copy_from_slice(&len.to_le_bytes());
+        node
+    }
+
+    fn xz_header() -> Vec<u8> {
+        let mut header = vec![0xfd, b'7', b'z', b'X', b'Z', 0x00, 0x00, 0x04];
+        header.extend_from_slice(&crc32(&[0x00, 0x04]).to_le_bytes());
+        header
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        let mut jffs2 = jffs2_node(0xe001, 44);
+        jffs2.extend(jffs2_node(0xe002, 70));
+        vec![
+            uimage_header("Linux Kernel Image", 1024),
+            trx_header(4096, 1),
+            squashfs_header(true, 4, 1 << 20),
+            squashfs_header(false, 3, 1 << 20),
+            cramfs_header(true, 4096),
+            cramfs_header(false, 4096),
+            jffs2,
+            vec![0x1f, 0x8b, 0x08, 0x08, 0, 0, 0, 0, 0, 3, b'f', b'w', 0],
+            vec![0x5d, 0, 0, 0x80, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            xz_header(),
+        ]
+    }
+
+    /// Deterministic noise, with the first byte of every magic mixed in
+    fn garbage(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..len)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                match i % 7 {
+                    0 => [0x27, b'H', b'h', b's', 0x45, 0x28, 0x85, 0x19, 0x1f, 0x5d, 0xfd][state as usize % 11],
+                    _ => state as u8,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc32_matches_zlib() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    #[test]
+    fn uimage_header_is_parsed() {
+        let hit = uimage(&uimage_header("Linux Kernel Image", 1024)).unwrap();
+        assert_eq!(hit.kind, ComponentKind::UImage);
+        assert_eq!(hit.size, Some(64 + 1024));
+        assert!(hit.description.contains("\"Linux Kernel Image\""));
+        assert!(hit.description.contains("load address: 0x80008000"));
+        assert!(hit.description.contains("compression: lzma"));
+
+        let mut corrupt = uimage_header("Linux Kernel Image", 1024);
+        corrupt[40] ^= 1;
+        assert!(uimage(&corrupt).is_none());
+    }
+
+    #[test]
+    fn trx_header_is_parsed() {
+        let hit = trx(&trx_header(4096, 1)).unwrap();
+        assert_eq!(hit.size, Some(4096));
+        assert!(hit.description.contains("version: 1"));
+        assert!(hit.description.contains("partition offsets: 0x1c"));
+        assert!(trx(&trx_header(4096, 3)).is_none());
+        assert!(trx(&trx_header(16, 1)).is_none());
+    }
+
+    #[test]
+    fn squashfs_header_is_parsed() {
+        let hit = squashfs(&squashfs_header(true, 4, 123_456)).unwrap();
+        assert_eq!(hit.size, Some(123_456));
+        assert!(hit.description.contains("little endian, version 4.0"));
+
+        let hit = squashfs(&squashfs_header(false, 3, 654_321)).unwrap();
+        assert_eq!(hit.size, Some(654_321));
+        assert!(hit.description.contains("big endian, version 3.0"));
+
+        let hit = squashfs(&squashfs_header(true, 2, 0)).unwrap();
+        assert_eq!(hit.size, None);
+        assert!(squashfs(&squashfs_header(true, 5, 0)).is_none());
+    }
+
+    #[test]
+    fn cramfs_header_is_parsed() {
+        assert_eq!(cramfs(&cramfs_header(true, 8192)).unwrap().size, Some(8192));
+        assert_eq!(cramfs(&cramfs_header(false, 8192)).unwrap().size, Some(8192));
+        let mut header = cramfs_header(true, 8192);
+        header[16] = b'c';
+        assert!(cramfs(&header).is_none());
+    }
+
+    #[test]
+    fn jffs2_nodes_are_walked() {
+        let mut data = jffs2_node(0xe001, 44);
+        data.extend(jffs2_node(0xe002, 70));
+        data.extend([0xff; 64]);
+        data.extend(jffs2_node(0x2003, 12));
+        let end = data.len();
+        data.extend(b"not a node at all");
+
+        let hit = jffs2(&data).unwrap();
+        assert_eq!(hit.size, Some(end));
+        assert!(hit.description.contains("3 nodes"));
+        assert!(jffs2(&jffs2_node(0x1234, 12)).is_none());
+    }
+
+    #[test]
+    fn jffs2_node_longer_than_the_data_is_cut() {
+        let data = jffs2_node(0xe001, 12);
+        let mut long = data.clone();
+        long[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(jffs2(&long).unwrap().size, Some(data.len()));
+    }
+
+    #[test]
+    fn streams_are_recognised() {
+        let hit = gzip(&[0x1f, 0x8b, 0x08, 0x08, 0, 0, 0, 0, 0, 3, b'f', b'w', 0]).unwrap();
+        assert!(hit.description.contains("original file name: \"fw\""));
+        assert!(gzip(&[0x1f, 0x8b, 0x08, 0x20, 0, 0, 0, 0, 0, 3]).is_none());
+
+        assert!(lzma(&[0x5d, 0, 0, 0x80, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_some());
+        assert!(lzma(&[0x5d, 0, 0, 0x70, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_none());
+
+        assert!(xz(&xz_header()).is_some());
+        let mut header = xz_header();
+        header[7] = 0x01;
+        assert!(xz(&header).is_none());
+    }
+
+    #[test]
+    fn scan_finds_components_in_order() {
+        let mut data = vec![0u8; 100];
+        let trx_at = data.len();
+        data.extend(trx_header(4096, 1));
+        let gzip_at = data.len();
+        data.extend([0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 3]);
+        data.extend([0u8; 30]);
+        let squashfs_at = data.len();
+        let mut squashfs = squashfs_header(true, 4, 256);
+        squashfs.resize(256, 0);
+        // a bogus signature inside the filesystem is skipped
+        squashfs[128..134].copy_from_slice(&xz_header()[..6]);
+        data.extend(squashfs);
+        data.extend([0u8; 16]);
+
+        let components = scan(&data);
+        let found: Vec<(ComponentKind, usize, usize)> = components.iter()
+            .map(|component| (component.kind, component.offset, component.size))
+            .collect();
+        assert_eq!(found, vec![
+            (ComponentKind::Trx, trx_at, data.len() - trx_at),
+            (ComponentKind::Gzip, gzip_at, squashfs_at - gzip_at),
+            (ComponentKind::SquashFs, squashfs_at, 256),
+        ]);
+    }
+
+    #[test]
+    fn truncated_headers_do_not_panic() {
+        for sample in samples() {
+            for len in 0..sample.len() {
+                let data = &sample[..len];
+                if !data.is_empty() {
+                    match_at(data);
+                }
+                for component in scan(data) {
+                    assert!(component.offset + component.size <= data.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_does_not_panic() {
+        let mut data = garbage(1 << 16);
+        // headers cut off by the end of the data
+        for sample in samples() {
+            data.extend(&sample[..sample.len() / 2]);
+        }
+        for component in scan(&data) {
+            assert!(component.offset + component.size <= data.len());
+        }
+    }
+}
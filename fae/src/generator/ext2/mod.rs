@@ -0,0 +1,420 @@
+//! Minimal ext2 support: an in-memory file tree, a writer that lays it out
+//! as a revision 1 ext2 filesystem and a reader to load one back.
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+mod reader;
+mod writer;
+pub use reader::Ext2Reader;
+pub use writer::Ext2Writer;
+
+/// Contents of a regular file
+#[derive(Clone, Debug)]
+pub enum FileData {
+    Bytes(Vec<u8>),
+    /// Read from the host when the filesystem is written
+    Host { path: PathBuf, size: u64 },
+}
+
+impl FileData {
+    pub fn len(&self) -> u64 {
+        match self {
+            FileData::Bytes(bytes) => bytes.len() as u64,
+            FileData::Host { size, .. } => *size,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum NodeKind {
+    Directory(BTreeMap<String, Node>),
+    File(FileData),
+    Symlink(String),
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub kind: NodeKind,
+    /// Permission bits, without the file type
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+}
+
+impl Node {
+    pub fn new(kind: NodeKind, mode: u32) -> Self {
+        Node { kind, mode, uid: 0, gid: 0, mtime: now() }
+    }
+
+    pub fn directory(mode: u32) -> Self {
+        Node::new(NodeKind::Directory(BTreeMap::new()), mode)
+    }
+
+    pub fn children(&self) -> Option<&BTreeMap<String, Node>> {
+        match &self.kind {
+            NodeKind::Directory(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    /// Number of nodes in this subtree, itself included
+    pub fn count(&self) -> usize {
+        1 + self.children().map_or(0, |children| children.values().map(Node::count).sum())
+    }
+
+    /// Apply `f` to this node and everything below it
+    pub fn for_each_mut(&mut self, f: &mut impl FnMut(&mut Node)) {
+        f(self);
+        if let NodeKind::Directory(children) = &mut self.kind {
+            for child in children.values_mut() {
+                child.for_each_mut(f);
+            }
+        }
+    }
+}
+
+pub fn now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// A directory tree to be written as a filesystem
+#[derive(Clone, Debug)]
+pub struct FileTree {
+    pub root: Node,
+}
+
+impl Default for FileTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileTree {
+    pub fn new() -> Self {
+        FileTree { root: Node::directory(0o755) }
+    }
+
+    /// Load a host directory. File contents are not read until written out.
+    pub fn from_dir(dir: &Path) -> std::io::Result<Self> {
+        let mut root = node_from_host(dir)?;
+        root.mode = 0o755;
+        Ok(FileTree { root })
+    }
+
+    /// Split a guest path into names, following symlinks of intermediate
+    /// directories the way the guest kernel would
+    fn resolve_parent(&self, path: &str) -> Option<(Vec<String>, String)> {
+        let mut names: Vec<String> = Vec::new();
+        let mut pending: Vec<String> = normal_names(path);
+        let name = pending.pop()?;
+        pending.reverse();
+
+        let mut hops = 0;
+        while let Some(next) = pending.pop() {
+            if next == ".." {
+                names.pop();
+                continue;
+            }
+            names.push(next);
+            if let Some(NodeKind::Symlink(target)) = self.lookup(&names).map(|node| &node.kind) {
+                if hops == 16 {
+                    return None;
+                }
+                hops += 1;
+                names.pop();
+                if target.starts_with('/') {
+                    names.clear();
+                }
+                pending.extend(target.split('/').filter(|part| !part.is_empty() && *part != ".").rev().map(String::from));
+            }
+        }
+        Some((names, name))
+    }
+
+    fn lookup(&self, names: &[String]) -> Option<&Node> {
+        let mut node = &self.root;
+        for name in names {
+            node = node.children()?.get(name)?;
+        }
+        Some(node)
+    }
+
+    fn lookup_mut(&mut self, names: &[String]) -> Option<&mut Node> {
+        let mut node = &mut self.root;
+        for name in names {
+            node = match &mut node.kind {
+                NodeKind::Directory(children) => children.get_mut(name)?,
+                _ => return None,
+            };
+        }
+        Some(node)
+    }
+
+    /// Names of the directory `path` points to, following every symlink
+    fn resolve_dir(&self, path: &str) -> Vec<String> {
+        self.resolve_parent(&format!("{}/_", path)).map_or(Vec::new(), |(names, _)| names)
+    }
+
+    /// Create a directory and any missing parents, like `mkdir -p`.
+    /// Symlinked directories are followed, existing non-directories are kept.
+    pub fn create_dir_all(&mut self, path: &str, mode: u32) {
+        let mut current = String::new();
+        for name in normal_names(path) {
+            current.push('/');
+            current.push_str(&name);
+            let mut names = self.resolve_dir(&current);
+            if self.lookup(&names).is_some() {
+                continue;
+            }
+            let Some(name) = names.pop() else {
+                continue;
+            };
+            self.create_dir_all(&format!("/{}", names.join("/")), mode);
+            if let Some(Node { kind: NodeKind::Directory(children), .. }) = self.lookup_mut(&names) {
+                children.insert(name, Node::directory(mode));
+            }
+        }
+    }
+
+    /// Put `node` at `path`, replacing what was there. Missing parents are created.
+    pub fn insert(&mut self, path: &str, node: Node) {
+        let Some((parent, name)) = self.resolve_parent(path) else {
+            // the root itself
+            self.root = node;
+            return;
+        };
+        self.create_dir_all(&format!("/{}", parent.join("/")), 0o755);
+        if let Some(Node { kind: NodeKind::Directory(children), .. }) = self.lookup_mut(&parent) {
+            children.insert(name, node);
+        }
+    }
+}
+
+/// Names of a guest path, `..` is kept so it can be resolved against symlinks
+fn normal_names(path: &str) -> Vec<String> {
+    Path::new(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            Component::ParentDir => Some("..".to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn node_from_host(path: &Path) -> std::io::Result<Node> {
+    let metadata = path.symlink_metadata()?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        let mut children = BTreeMap::new();
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let child_type = entry.file_type()?;
+            // fifos and sockets have no place in a firmware image
+            if child_type.is_fifo() || child_type.is_socket() {
+                continue;
+            }
+            children.insert(entry.file_name().to_string_lossy().into_owned(), node_from_host(&entry.path())?);
+        }
+        NodeKind::Directory(children)
+    } else if file_type.is_symlink() {
+        NodeKind::Symlink(std::fs::read_link(path)?.to_string_lossy().into_owned())
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        let rdev = metadata.rdev();
+        let major = (((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff)) as u32;
+        let minor = ((rdev & 0xff) | ((rdev >> 12) & !0xff)) as u32;
+        if file_type.is_char_device() {
+            NodeKind::CharDevice { major, minor }
+        } else {
+            NodeKind::BlockDevice { major, minor }
+        }
+    } else {
+        NodeKind::File(FileData::Host { path: path.to_path_buf(), size: metadata.len() })
+    };
+
+    Ok(Node {
+        kind,
+        mode: metadata.mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: metadata.mtime() as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MTIME: u32 = 1_700_000_000;
+
+    fn node(kind: NodeKind, mode: u32) -> Node {
+        Node { kind, mode, uid: 0, gid: 0, mtime: MTIME }
+    }
+
+    fn file(bytes: Vec<u8>) -> Node {
+        node(NodeKind::File(FileData::Bytes(bytes)), 0o644)
+    }
+
+    /// Write `tree` into a fresh file and read it back
+    fn round_trip(tree: &FileTree, size: Option<u64>) -> std::io::Result<FileTree> {
+        let mut image = tempfile::tempfile()?;
+        let writer = Ext2Writer::new(tree);
+        let writer = match size {
+            Some(size) => writer.size(size),
+            None => writer,
+        };
+        // leave room in front like the partition offset of a disk
+        writer.write(&mut image, 512 * 2048)?;
+        Ext2Reader::open(image, 512 * 2048)?.read_tree()
+    }
+
+    fn contents(data: &FileData) -> Vec<u8> {
+        match data {
+            FileData::Bytes(bytes) => bytes.clone(),
+            FileData::Host { path, .. } => std::fs::read(path).unwrap(),
+        }
+    }
+
+    fn assert_same(path: &str, expected: &Node, actual: &Node) {
+        assert_eq!((expected.mode, expected.uid, expected.gid, expected.mtime),
+                   (actual.mode, actual.uid, actual.gid, actual.mtime), "metadata of {}", path);
+        match (&expected.kind, &actual.kind) {
+            (NodeKind::Directory(expected), NodeKind::Directory(actual)) => {
+                let names = |children: &BTreeMap<String, Node>| children.keys().cloned().collect::<Vec<_>>();
+                assert_eq!(names(expected), names(actual), "entries of {}", path);
+                for (name, child) in expected {
+                    assert_same(&format!("{}/{}", path, name), child, &actual[name]);
+                }
+            }
+            (NodeKind::File(expected), NodeKind::File(actual)) => {
+                assert!(contents(expected) == contents(actual), "contents of {}", path);
+            }
+            (NodeKind::Symlink(expected), NodeKind::Symlink(actual)) => assert_eq!(expected, actual, "target of {}", path),
+            (NodeKind::CharDevice { major, minor }, NodeKind::CharDevice { major: a, minor: b })
+            | (NodeKind::BlockDevice { major, minor }, NodeKind::BlockDevice { major: a, minor: b }) => {
+                assert_eq!((major, minor), (a, b), "device numbers of {}", path);
+            }
+            (expected, actual) => panic!("{} was {:?}, read back {:?}", path, expected, actual),
+        }
+    }
+
+    /// The tree read back has the writer's own lost+found on top
+    fn without_lost_found(mut tree: FileTree) -> FileTree {
+        if let NodeKind::Directory(children) = &mut tree.root.kind {
+            let lost_found = children.remove("lost+found").expect("no lost+found written");
+            assert_eq!(lost_found.mode, 0o700);
+            assert!(lost_found.children().is_some_and(BTreeMap::is_empty));
+        }
+        tree
+    }
+
+    fn sample_tree() -> FileTree {
+        let mut tree = FileTree::new();
+        tree.root.mtime = MTIME;
+        let mut add = |path: &str, node: Node| {
+            tree.create_dir_all(Path::new(path).parent().unwrap().to_str().unwrap(), 0o755);
+            tree.insert(path, node);
+        };
+        add("/etc/passwd", file(b"root:x:0:0:root:/root:/bin/sh\n".to_vec()));
+        add("/empty", file(Vec::new()));
+        // 12 direct blocks, a full single indirect block and a few more
+        // through the double indirect one
+        let big: Vec<u8> = (0..(12 + 1024 + 3) * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        add("/usr/lib/libbig.so", file(big));
+        add("/bin/sh", node(NodeKind::Symlink("busybox".to_string()), 0o777));
+        let long = format!("/usr/lib/{}/target", "a".repeat(80));
+        add("/lib/long-link", node(NodeKind::Symlink(long), 0o777));
+        add("/dev/console", node(NodeKind::CharDevice { major: 5, minor: 1 }, 0o622));
+        add("/dev/mtdblock0", node(NodeKind::BlockDevice { major: 31, minor: 0 }, 0o644));
+        add("/dev/big", node(NodeKind::CharDevice { major: 300, minor: 70000 }, 0o600));
+        add("/a/b/c/d/e/deep", file(b"deep".to_vec()));
+        let mut owned = file(b"owned".to_vec());
+        owned.uid = 100_000;
+        owned.gid = 70_000;
+        owned.mode = 0o4755;
+        add("/home/user/owned", owned);
+        tree.root.for_each_mut(&mut |node| node.mtime = MTIME);
+        tree
+    }
+
+    #[test]
+    fn round_trip_keeps_everything() {
+        let tree = sample_tree();
+        let read = without_lost_found(round_trip(&tree, None).unwrap());
+        assert_same("", &tree.root, &read.root);
+    }
+
+    #[test]
+    fn round_trip_with_explicit_size() {
+        let tree = sample_tree();
+        let read = without_lost_found(round_trip(&tree, Some(64 << 20)).unwrap());
+        assert_same("", &tree.root, &read.root);
+    }
+
+    #[test]
+    fn host_files_are_copied() {
+        let mut source = tempfile::NamedTempFile::new().unwrap();
+        let data: Vec<u8> = (0..20_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        std::io::Write::write_all(&mut source, &data).unwrap();
+        let mut tree = FileTree::new();
+        tree.root.mtime = MTIME;
+        let host = FileData::Host { path: source.path().to_path_buf(), size: data.len() as u64 };
+        tree.insert("/agent", node(NodeKind::File(host), 0o755));
+        let read = without_lost_found(round_trip(&tree, None).unwrap());
+        assert_same("", &tree.root, &read.root);
+    }
+
+    #[test]
+    fn existing_lost_found_is_reused() {
+        let mut tree = FileTree::new();
+        tree.root.mtime = MTIME;
+        tree.insert("/lost+found", node(NodeKind::Directory(BTreeMap::new()), 0o755));
+        tree.insert("/lost+found/kept", file(b"kept".to_vec()));
+        let read = round_trip(&tree, None).unwrap();
+        assert_same("", &tree.root, &read.root);
+    }
+
+    #[test]
+    fn lost_found_file_gets_a_single_entry() {
+        let mut tree = FileTree::new();
+        tree.root.mtime = MTIME;
+        tree.insert("/lost+found", file(b"not a directory".to_vec()));
+        // a second entry of the same name makes the reader fail
+        let read = round_trip(&tree, None).unwrap();
+        assert_same("", &tree.root, &read.root);
+    }
+
+    #[test]
+    fn too_small_size_is_rejected() {
+        let tree = sample_tree();
+        let err = round_trip(&tree, Some(1 << 20)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reader_rejects_garbage() {
+        let image = std::io::Cursor::new(vec![0u8; 8192]);
+        assert!(Ext2Reader::open(image, 0).is_err());
+        let short = std::io::Cursor::new(vec![0u8; 100]);
+        assert!(Ext2Reader::open(short, 0).is_err());
+    }
+
+    #[test]
+    fn insert_follows_symlinked_directories() {
+        let mut tree = FileTree::new();
+        tree.insert("/tmp/var", Node::directory(0o755));
+        tree.insert("/var", Node::new(NodeKind::Symlink("/tmp/var".to_string()), 0o777));
+        tree.insert("/var/run/pid", file(Vec::new()));
+        let var = tree.lookup(&["tmp".to_string(), "var".to_string()]).unwrap();
+        assert!(var.children().unwrap().contains_key("run"));
+        assert!(matches!(tree.lookup(&["var".to_string()]).unwrap().kind, NodeKind::Symlink(_)));
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+use super::writer::{EXT2_MAGIC, ROOT_INO};
+use super::{FileData, FileTree, Node, NodeKind};
+
+/// The inode fields we care about
+struct Inode {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u32,
+    block: [u32; 15],
+}
+
+/// Reads an ext2 filesystem back into a `FileTree`
+pub struct Ext2Reader<R: Read + Seek> {
+    file: R,
+    offset: u64,
+    block_size: u64,
+    inodes_per_group: u64,
+    inode_size: u64,
+    /// First block of the inode table of each group
+    inode_tables: Vec<u64>,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+impl<R: Read + Seek> Ext2Reader<R> {
+    /// Open the filesystem starting at byte `offset` of `file`
+    pub fn open(mut file: R, offset: u64) -> std::io::Result<Self> {
+        let mut superblock = [0u8; 1024];
+        file.seek(SeekFrom::Start(offset + 1024))?;
+        file.read_exact(&mut superblock)?;
+        let u32_at = |at: usize| u32::from_le_bytes(superblock[at..at + 4].try_into().unwrap()) as u64;
+        let u16_at = |at: usize| u16::from_le_bytes(superblock[at..at + 2].try_into().unwrap());
+        if u16_at(56) != EXT2_MAGIC {
+            return Err(invalid("not an ext2 filesystem"));
+        }
+
+        let blocks_count = u32_at(4);
+        let first_data_block = u32_at(20);
+        let block_size = 1024 << u32_at(24);
+        let blocks_per_group = u32_at(32);
+        let inodes_per_group = u32_at(40);
+        let inode_size = if u32_at(76) == 0 { 128 } else { u16_at(88) as u64 };
+        if blocks_per_group == 0 || inodes_per_group == 0 {
+            return Err(invalid("corrupt ext2 superblock"));
+        }
+        let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+
+        let mut descriptors = vec![0u8; (groups * 32) as usize];
+        file.seek(SeekFrom::Start(offset + (first_data_block + 1) * block_size))?;
+        file.read_exact(&mut descriptors)?;
+        let inode_tables = descriptors
+            .chunks(32)
+            .map(|descriptor| u32::from_le_bytes(descriptor[8..12].try_into().unwrap()) as u64)
+            .collect();
+
+        Ok(Ext2Reader { file, offset, block_size, inodes_per_group, inode_size, inode_tables })
+    }
+
+    fn read_block(&mut self, block: u64) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0u8; self.block_size as usize];
+        self.file.seek(SeekFrom::Start(self.offset + block * self.block_size))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_inode(&mut self, ino: u32) -> std::io::Result<Inode> {
+        let index = ino as u64 - 1;
+        let table = *self
+            .inode_tables
+            .get((index / self.inodes_per_group) as usize)
+            .ok_or_else(|| invalid("inode number out of range"))?;
+        let at = table * self.block_size + (index % self.inodes_per_group) * self.inode_size;
+        let mut raw = [0u8; 128];
+        self.file.seek(SeekFrom::Start(self.offset + at))?;
+        self.file.read_exact(&mut raw)?;
+
+        let u32_at = |at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_le_bytes(raw[at..at + 2].try_into().unwrap()) as u32;
+        let mode = u16_at(0);
+        let mut size = u32_at(4) as u64;
+        if mode & 0o170000 == 0o100000 {
+            size |= (u32_at(108) as u64) << 32;
+        }
+        let mut block = [0u32; 15];
+        for (i, pointer) in block.iter_mut().enumerate() {
+            *pointer = u32_at(40 + i * 4);
+        }
+        Ok(Inode {
+            mode,
+            uid: u16_at(2) | (u16_at(120) << 16),
+            gid: u16_at(24) | (u16_at(122) << 16),
+            size,
+            mtime: u32_at(16),
+            block,
+        })
+    }
+
+    /// Data blocks of an inode in file order, 0 for holes
+    fn block_list(&mut self, inode: &Inode) -> std::io::Result<Vec<u32>> {
+        let count = inode.size.div_ceil(self.block_size) as usize;
+        let mut blocks: Vec<u32> = inode.block[..12].to_vec();
+        for (level, pointer) in [(1, inode.block[12]), (2, inode.block[13]), (3, inode.block[14])] {
+            if blocks.len() >= count {
+                break;
+            }
+            self.collect_indirect(pointer, level, count, &mut blocks)?;
+        }
+        blocks.truncate(count);
+        Ok(blocks)
+    }
+
+    fn collect_indirect(&mut self, block: u32, level: u32, count: usize, blocks: &mut Vec<u32>) -> std::io::Result<()> {
+        let pointers = self.block_size as usize / 4;
+        if block == 0 {
+            // a hole covering everything this block would address
+            let span = pointers.pow(level);
+            blocks.resize((blocks.len() + span).min(count), 0);
+            return Ok(());
+        }
+        let data = self.read_block(block as u64)?;
+        for chunk in data.chunks(4) {
+            if blocks.len() >= count {
+                break;
+            }
+            let pointer = u32::from_le_bytes(chunk.try_into().unwrap());
+            if level == 1 {
+                blocks.push(pointer);
+            } else {
+                self.collect_indirect(pointer, level - 1, count, blocks)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self, inode: &Inode) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(inode.size as usize);
+        for block in self.block_list(inode)? {
+            if block == 0 {
+                data.resize(data.len() + self.block_size as usize, 0);
+            } else {
+                data.extend(self.read_block(block as u64)?);
+            }
+        }
+        data.truncate(inode.size as usize);
+        Ok(data)
+    }
+
+    fn read_node(&mut self, ino: u32, depth: usize) -> std::io::Result<Node> {
+        if depth > 256 {
+            return Err(invalid("directory loop in ext2 filesystem"));
+        }
+        let inode = self.read_inode(ino)?;
+        let kind = match inode.mode & 0o170000 {
+            0o040000 => {
+                let data = self.read_data(&inode)?;
+                let mut children = BTreeMap::new();
+                let mut at = 0;
+                while at + 8 <= data.len() {
+                    let child = u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+                    let rec_len = u16::from_le_bytes(data[at + 4..at + 6].try_into().unwrap()) as usize;
+                    let name_len = data[at + 6] as usize;
+                    if rec_len < 8 || at + 8 + name_len > data.len() {
+                        return Err(invalid("corrupt ext2 directory entry"));
+                    }
+                    let name = String::from_utf8_lossy(&data[at + 8..at + 8 + name_len]).into_owned();
+                    if child != 0 && name != "." && name != ".." {
+                        let node = self.read_node(child, depth + 1)?;
+                        if children.insert(name, node).is_some() {
+                            return Err(invalid("duplicate ext2 directory entry"));
+                        }
+                    }
+                    at += rec_len;
+                }
+                NodeKind::Directory(children)
+            }
+            0o100000 => NodeKind::File(FileData::Bytes(self.read_data(&inode)?)),
+            0o120000 => {
+                let target = if inode.size < 60 {
+                    inode.block.iter().flat_map(|pointer| pointer.to_le_bytes()).take(inode.size as usize).collect()
+                } else {
+                    self.read_data(&inode)?
+                };
+                NodeKind::Symlink(String::from_utf8_lossy(&target).into_owned())
+            }
+            kind @ (0o020000 | 0o060000) => {
+                let (major, minor) = if inode.block[0] != 0 {
+                    (inode.block[0] >> 8 & 0xff, inode.block[0] & 0xff)
+                } else {
+                    let dev = inode.block[1];
+                    ((dev >> 8) & 0xfff, (dev & 0xff) | ((dev >> 12) & 0xfff00))
+                };
+                if kind == 0o020000 {
+                    NodeKind::CharDevice { major, minor }
+                } else {
+                    NodeKind::BlockDevice { major, minor }
+                }
+            }
+            _ => return Err(invalid("unsupported inode type in ext2 filesystem")),
+        };
+        Ok(Node { kind, mode: inode.mode & 0o7777, uid: inode.uid, gid: inode.gid, mtime: inode.mtime })
+    }
+
+    /// Load the whole filesystem, file contents included
+    pub fn read_tree(&mut self) -> std::io::Result<FileTree> {
+        Ok(FileTree { root: self.read_node(ROOT_INO, 0)? })
+    }
+}
@@ -0,0 +1,569 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use super::{now, FileData, FileTree, Node, NodeKind};
+
+pub const BLOCK_SIZE: u64 = 4096;
+pub const INODE_SIZE: u64 = 128;
+pub const ROOT_INO: u32 = 2;
+pub const LOST_FOUND_INO: u32 = 11;
+pub const EXT2_MAGIC: u16 = 0xef53;
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2;
+pub const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x2;
+/// Block pointers held by one indirect block
+const POINTERS: u64 = BLOCK_SIZE / 4;
+/// With an explicit size, one inode per 16K of space like mke2fs does
+const BYTES_PER_INODE: u64 = 16384;
+
+/// Geometry of the filesystem being written
+#[derive(Debug)]
+struct Layout {
+    blocks_count: u64,
+    groups: u64,
+    blocks_per_group: u64,
+    inodes_per_group: u64,
+    inode_table_blocks: u64,
+    gdt_blocks: u64,
+}
+
+impl Layout {
+    /// superblock, descriptors, two bitmaps and the inode table
+    fn overhead(&self) -> u64 {
+        1 + self.gdt_blocks + 2 + self.inode_table_blocks
+    }
+
+    fn group_start(&self, group: u64) -> u64 {
+        group * self.blocks_per_group
+    }
+
+    fn group_blocks(&self, group: u64) -> u64 {
+        self.blocks_per_group.min(self.blocks_count - self.group_start(group))
+    }
+
+    fn compute(blocks_count: u64, inodes: u64, ratio_inodes: bool) -> Layout {
+        let blocks_per_group = BLOCK_SIZE * 8;
+        let groups = blocks_count.div_ceil(blocks_per_group);
+        let inodes_per_block = BLOCK_SIZE / INODE_SIZE;
+        let mut inodes_per_group = inodes.div_ceil(groups);
+        if ratio_inodes {
+            inodes_per_group = inodes_per_group.max(blocks_count * BLOCK_SIZE / BYTES_PER_INODE / groups);
+        }
+        let inodes_per_group = inodes_per_group
+            .div_ceil(inodes_per_block)
+            .max(1)
+            * inodes_per_block;
+        Layout {
+            blocks_count,
+            groups,
+            blocks_per_group,
+            inodes_per_group,
+            inode_table_blocks: inodes_per_group * INODE_SIZE / BLOCK_SIZE,
+            gdt_blocks: (groups * 32).div_ceil(BLOCK_SIZE),
+        }
+    }
+
+    fn data_capacity(&self) -> u64 {
+        self.blocks_count - self.groups * self.overhead()
+    }
+
+    fn is_valid(&self, data_blocks: u64) -> bool {
+        let last = self.group_blocks(self.groups - 1);
+        self.inodes_per_group <= BLOCK_SIZE * 8
+            && last > self.overhead()
+            && self.blocks_count >= self.groups * self.overhead() + data_blocks
+    }
+}
+
+/// Blocks needed for indirect pointers of a file with `blocks` data blocks
+fn indirect_blocks(blocks: u64) -> u64 {
+    let mut left = blocks.saturating_sub(12);
+    if left == 0 {
+        return 0;
+    }
+    let mut total = 1;
+    left = left.saturating_sub(POINTERS);
+    if left == 0 {
+        return total;
+    }
+    let double = left.min(POINTERS * POINTERS);
+    total += 1 + double.div_ceil(POINTERS);
+    left -= double;
+    if left == 0 {
+        return total;
+    }
+    total + 1 + left.div_ceil(POINTERS * POINTERS) + left.div_ceil(POINTERS)
+}
+
+fn rec_len(name: &str) -> usize {
+    (8 + name.len()).div_ceil(4) * 4
+}
+
+fn file_type(node: &Node) -> u8 {
+    match node.kind {
+        NodeKind::File(_) => 1,
+        NodeKind::Directory(_) => 2,
+        NodeKind::CharDevice { .. } => 3,
+        NodeKind::BlockDevice { .. } => 4,
+        NodeKind::Symlink(_) => 7,
+    }
+}
+
+fn mode_type(node: &Node) -> u32 {
+    match node.kind {
+        NodeKind::File(_) => 0o100000,
+        NodeKind::Directory(_) => 0o040000,
+        NodeKind::CharDevice { .. } => 0o020000,
+        NodeKind::BlockDevice { .. } => 0o060000,
+        NodeKind::Symlink(_) => 0o120000,
+    }
+}
+
+/// Pack directory entries into blocks, entries never cross a block boundary
+fn directory_blocks(entries: &[(String, u32, u8)]) -> Vec<Vec<u8>> {
+    let block_size = BLOCK_SIZE as usize;
+    let mut blocks = vec![Vec::new()];
+    // position of the last entry in the current block, its rec_len is
+    // stretched to the end of the block once the block is full
+    let mut last = 0;
+    for (name, ino, kind) in entries {
+        let len = rec_len(name);
+        if blocks.last().unwrap().len() + len > block_size {
+            let block = blocks.last_mut().unwrap();
+            let stretched = (block_size - last) as u16;
+            block[last + 4..last + 6].copy_from_slice(&stretched.to_le_bytes());
+            block.resize(block_size, 0);
+            blocks.push(Vec::new());
+        }
+        let block = blocks.last_mut().unwrap();
+        last = block.len();
+        block.extend_from_slice(&ino.to_le_bytes());
+        block.extend_from_slice(&(len as u16).to_le_bytes());
+        block.push(name.len() as u8);
+        block.push(*kind);
+        block.extend_from_slice(name.as_bytes());
+        block.resize(last + len, 0);
+    }
+    let block = blocks.last_mut().unwrap();
+    let stretched = (block_size - last) as u16;
+    block[last + 4..last + 6].copy_from_slice(&stretched.to_le_bytes());
+    block.resize(block_size, 0);
+    blocks
+}
+
+/// A node with its inode number and directory entries resolved
+struct Entry<'a> {
+    node: &'a Node,
+    /// name, inode and file type of each entry, `.` and `..` included
+    dirents: Vec<(String, u32, u8)>,
+}
+
+impl Entry<'_> {
+    fn content_blocks(&self) -> u64 {
+        match &self.node.kind {
+            NodeKind::Directory(_) => directory_blocks(&self.dirents).len() as u64,
+            NodeKind::File(data) => data.len().div_ceil(BLOCK_SIZE),
+            NodeKind::Symlink(target) if target.len() >= 60 => 1,
+            _ => 0,
+        }
+    }
+
+    fn total_blocks(&self) -> u64 {
+        let content = self.content_blocks();
+        content + indirect_blocks(content)
+    }
+}
+
+/// Hands out free data blocks group by group and tracks the bitmaps
+struct Allocator<'a> {
+    layout: &'a Layout,
+    bitmaps: Vec<Vec<u8>>,
+    group: u64,
+    next: u64,
+}
+
+impl<'a> Allocator<'a> {
+    fn new(layout: &'a Layout) -> Self {
+        let bitmaps = (0..layout.groups)
+            .map(|group| {
+                let mut bitmap = vec![0u8; BLOCK_SIZE as usize];
+                let blocks = layout.group_blocks(group);
+                for bit in (0..layout.overhead()).chain(blocks..BLOCK_SIZE * 8) {
+                    bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+                }
+                bitmap
+            })
+            .collect();
+        Allocator { layout, bitmaps, group: 0, next: layout.overhead() }
+    }
+
+    fn alloc(&mut self) -> std::io::Result<u32> {
+        while self.group < self.layout.groups {
+            if self.next < self.layout.group_blocks(self.group) {
+                let bit = self.next;
+                self.bitmaps[self.group as usize][(bit / 8) as usize] |= 1 << (bit % 8);
+                self.next += 1;
+                return Ok((self.layout.group_start(self.group) + bit) as u32);
+            }
+            self.group += 1;
+            self.next = self.layout.overhead();
+        }
+        Err(Error::new(ErrorKind::StorageFull, "ext2 image is out of blocks"))
+    }
+
+    fn free_blocks(&self, group: u64) -> u64 {
+        let used: u64 = self.bitmaps[group as usize].iter().map(|byte| byte.count_ones() as u64).sum();
+        BLOCK_SIZE * 8 - used
+    }
+}
+
+/// Writes a `FileTree` as an ext2 filesystem
+pub struct Ext2Writer<'a> {
+    tree: &'a FileTree,
+    size: Option<u64>,
+}
+
+impl<'a> Ext2Writer<'a> {
+    pub fn new(tree: &'a FileTree) -> Self {
+        Ext2Writer { tree, size: None }
+    }
+
+    /// Make the filesystem exactly `size` bytes (rounded down to whole
+    /// blocks) instead of sizing it from the content
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    fn entries<'b>(&self, lost_found: &'b Node) -> Vec<Option<Entry<'b>>>
+    where
+        'a: 'b,
+    {
+        let mut entries: Vec<Option<Entry<'b>>> = (0..=LOST_FOUND_INO).map(|_| None).collect();
+        // a lost+found of the rootfs takes inode 11 whatever it is, so the
+        // root never gets a second entry of that name
+        let has_lost_found = self.tree.root.children().is_some_and(|children| children.contains_key("lost+found"));
+        if !has_lost_found {
+            entries[LOST_FOUND_INO as usize] = Some(Entry {
+                node: lost_found,
+                dirents: vec![(".".to_string(), LOST_FOUND_INO, 2), ("..".to_string(), ROOT_INO, 2)],
+            });
+        }
+
+        // depth first, inode numbers are handed out before descending
+        let mut stack = vec![(&self.tree.root, ROOT_INO, ROOT_INO)];
+        while let Some((node, ino, parent)) = stack.pop() {
+            let mut dirents = Vec::new();
+            if let Some(children) = node.children() {
+                dirents.push((".".to_string(), ino, 2));
+                dirents.push(("..".to_string(), parent, 2));
+                if ino == ROOT_INO && !has_lost_found {
+                    dirents.push(("lost+found".to_string(), LOST_FOUND_INO, 2));
+                }
+                for (name, child) in children {
+                    let child_ino = if ino == ROOT_INO && name == "lost+found" {
+                        LOST_FOUND_INO
+                    } else {
+                        entries.push(None);
+                        (entries.len() - 1) as u32
+                    };
+                    dirents.push((name.clone(), child_ino, file_type(child)));
+                    stack.push((child, child_ino, ino));
+                }
+            }
+            entries[ino as usize] = Some(Entry { node, dirents });
+        }
+        entries
+    }
+
+    fn layout(&self, entries: &[Option<Entry>]) -> std::io::Result<Layout> {
+        let data_blocks: u64 = entries.iter().flatten().map(Entry::total_blocks).sum();
+        let inodes = entries.len() as u64 - 1;
+
+        if let Some(size) = self.size {
+            let mut blocks_count = size / BLOCK_SIZE;
+            let mut layout = Layout::compute(blocks_count, inodes, true);
+            // a trailing group too small for its own metadata is dropped
+            if layout.groups > 1 && layout.group_blocks(layout.groups - 1) <= layout.overhead() {
+                blocks_count = layout.group_start(layout.groups - 1);
+                layout = Layout::compute(blocks_count, inodes, true);
+            }
+            if !layout.is_valid(data_blocks) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} bytes are too small for the ext2 image, {} blocks of data and {} inodes",
+                        size, data_blocks, inodes),
+                ));
+            }
+            return Ok(layout);
+        }
+
+        // leave some room for files the guest creates at runtime
+        let wanted = data_blocks + data_blocks / 10 + 64;
+        let inodes = inodes + inodes / 10 + 64;
+        let mut blocks_count = wanted + 16;
+        loop {
+            let layout = Layout::compute(blocks_count, inodes, false);
+            if layout.is_valid(0) && layout.data_capacity() >= wanted {
+                return Ok(layout);
+            }
+            blocks_count = (blocks_count + wanted - layout.data_capacity().min(wanted))
+                .max(blocks_count + layout.overhead() + 1);
+        }
+    }
+
+    /// Write the filesystem into `file` starting at byte `offset`.
+    /// Returns the size of the filesystem in bytes.
+    pub fn write(&self, file: &mut File, offset: u64) -> std::io::Result<u64> {
+        let lost_found = Node::directory(0o700);
+        let entries = self.entries(&lost_found);
+        let layout = self.layout(&entries)?;
+        let mut allocator = Allocator::new(&layout);
+        let write_block = |file: &mut File, block: u64, data: &[u8]| -> std::io::Result<()> {
+            file.seek(SeekFrom::Start(offset + block * BLOCK_SIZE))?;
+            file.write_all(data)
+        };
+
+        let inodes_count = layout.inodes_per_group * layout.groups;
+        if (entries.len() as u64 - 1) > inodes_count {
+            return Err(Error::new(ErrorKind::StorageFull, "ext2 image is out of inodes"));
+        }
+        let mut inode_tables = vec![vec![0u8; (layout.inode_table_blocks * BLOCK_SIZE) as usize]; layout.groups as usize];
+        let mut used_dirs = vec![0u64; layout.groups as usize];
+        let mut large_file = false;
+
+        for (ino, entry) in entries.iter().enumerate() {
+            let Some(entry) = entry else {
+                continue;
+            };
+            let node = entry.node;
+
+            // content blocks first, then the indirect blocks pointing at them
+            let content_blocks = entry.content_blocks();
+            let mut blocks = Vec::with_capacity(content_blocks as usize);
+            for _ in 0..content_blocks {
+                blocks.push(allocator.alloc()?);
+            }
+            let mut size = 0u64;
+            match &node.kind {
+                NodeKind::Directory(_) => {
+                    for (block, data) in blocks.iter().zip(directory_blocks(&entry.dirents)) {
+                        write_block(file, *block as u64, &data)?;
+                    }
+                    size = content_blocks * BLOCK_SIZE;
+                    used_dirs[(ino as u64 - 1) as usize / layout.inodes_per_group as usize] += 1;
+                }
+                NodeKind::File(data) => {
+                    size = data.len();
+                    large_file |= size >= 1 << 31;
+                    let mut buffer = vec![0u8; BLOCK_SIZE as usize];
+                    match data {
+                        FileData::Bytes(bytes) => {
+                            for (block, chunk) in blocks.iter().zip(bytes.chunks(BLOCK_SIZE as usize)) {
+                                buffer.fill(0);
+                                buffer[..chunk.len()].copy_from_slice(chunk);
+                                write_block(file, *block as u64, &buffer)?;
+                            }
+                        }
+                        FileData::Host { path, .. } => {
+                            let mut source = File::open(path)?;
+                            for block in &blocks {
+                                buffer.fill(0);
+                                let mut filled = 0;
+                                while filled < buffer.len() {
+                                    let read = source.read(&mut buffer[filled..])?;
+                                    if read == 0 {
+                                        break;
+                                    }
+                                    filled += read;
+                                }
+                                write_block(file, *block as u64, &buffer)?;
+                            }
+                        }
+                    }
+                }
+                NodeKind::Symlink(target) => {
+                    size = target.len() as u64;
+                    if let Some(block) = blocks.first() {
+                        let mut buffer = target.as_bytes().to_vec();
+                        buffer.resize(BLOCK_SIZE as usize, 0);
+                        write_block(file, *block as u64, &buffer)?;
+                    }
+                }
+                _ => {}
+            }
+
+            let mut i_block = [0u32; 15];
+            match &node.kind {
+                NodeKind::Symlink(target) if target.len() < 60 => {
+                    // fast symlink, the target lives in the block pointers
+                    let mut inline = [0u8; 60];
+                    inline[..target.len()].copy_from_slice(target.as_bytes());
+                    for (i, pointer) in i_block.iter_mut().enumerate() {
+                        *pointer = u32::from_le_bytes(inline[i * 4..i * 4 + 4].try_into().unwrap());
+                    }
+                }
+                NodeKind::CharDevice { major, minor } | NodeKind::BlockDevice { major, minor } => {
+                    if *major < 256 && *minor < 256 {
+                        i_block[0] = (major << 8) | minor;
+                    } else {
+                        i_block[1] = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+                    }
+                }
+                _ => {
+                    let direct = blocks.len().min(12);
+                    i_block[..direct].copy_from_slice(&blocks[..direct]);
+                    let mut rest = &blocks[direct..];
+                    for (level, slot) in [(1, 12), (2, 13), (3, 14)] {
+                        if rest.is_empty() {
+                            break;
+                        }
+                        i_block[slot] = write_indirect(file, offset, &mut allocator, level, &mut rest)?;
+                    }
+                }
+            }
+
+            let links = match node.children() {
+                Some(_) => 2 + entry.dirents.iter().skip(2).filter(|(_, _, kind)| *kind == 2).count() as u16,
+                None => 1,
+            };
+            let mut inode = [0u8; INODE_SIZE as usize];
+            let mode = (mode_type(node) | (node.mode & 0o7777)) as u16;
+            inode[0..2].copy_from_slice(&mode.to_le_bytes());
+            inode[2..4].copy_from_slice(&(node.uid as u16).to_le_bytes());
+            inode[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+            for time in [8, 12, 16] {
+                inode[time..time + 4].copy_from_slice(&node.mtime.to_le_bytes());
+            }
+            inode[24..26].copy_from_slice(&(node.gid as u16).to_le_bytes());
+            inode[26..28].copy_from_slice(&links.to_le_bytes());
+            let sectors = entry.total_blocks() * BLOCK_SIZE / 512;
+            inode[28..32].copy_from_slice(&(sectors as u32).to_le_bytes());
+            for (i, pointer) in i_block.iter().enumerate() {
+                inode[40 + i * 4..44 + i * 4].copy_from_slice(&pointer.to_le_bytes());
+            }
+            if let NodeKind::File(_) = node.kind {
+                inode[108..112].copy_from_slice(&((size >> 32) as u32).to_le_bytes());
+            }
+            inode[120..122].copy_from_slice(&((node.uid >> 16) as u16).to_le_bytes());
+            inode[122..124].copy_from_slice(&((node.gid >> 16) as u16).to_le_bytes());
+
+            let index = ino as u64 - 1;
+            let group = (index / layout.inodes_per_group) as usize;
+            let at = ((index % layout.inodes_per_group) * INODE_SIZE) as usize;
+            inode_tables[group][at..at + INODE_SIZE as usize].copy_from_slice(&inode);
+        }
+
+        // inode bitmaps: every inode up to the last one handed out is in use,
+        // reserved ones included
+        let used_inodes = entries.len() as u64 - 1;
+        let mut descriptors = Vec::new();
+        let mut free_blocks_total = 0;
+        let mut free_inodes_total = 0;
+        for group in 0..layout.groups {
+            let mut inode_bitmap = vec![0u8; BLOCK_SIZE as usize];
+            let first = group * layout.inodes_per_group;
+            let used = used_inodes.saturating_sub(first).min(layout.inodes_per_group);
+            for bit in (0..used).chain(layout.inodes_per_group..BLOCK_SIZE * 8) {
+                inode_bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+            let free_blocks = allocator.free_blocks(group);
+            let free_inodes = layout.inodes_per_group - used;
+            free_blocks_total += free_blocks;
+            free_inodes_total += free_inodes;
+
+            let start = layout.group_start(group);
+            let block_bitmap = start + 1 + layout.gdt_blocks;
+            let mut descriptor = [0u8; 32];
+            descriptor[0..4].copy_from_slice(&(block_bitmap as u32).to_le_bytes());
+            descriptor[4..8].copy_from_slice(&(block_bitmap as u32 + 1).to_le_bytes());
+            descriptor[8..12].copy_from_slice(&(block_bitmap as u32 + 2).to_le_bytes());
+            descriptor[12..14].copy_from_slice(&(free_blocks as u16).to_le_bytes());
+            descriptor[14..16].copy_from_slice(&(free_inodes as u16).to_le_bytes());
+            descriptor[16..18].copy_from_slice(&(used_dirs[group as usize] as u16).to_le_bytes());
+            descriptors.extend_from_slice(&descriptor);
+
+            write_block(file, block_bitmap, &allocator.bitmaps[group as usize])?;
+            write_block(file, block_bitmap + 1, &inode_bitmap)?;
+            write_block(file, block_bitmap + 2, &inode_tables[group as usize])?;
+        }
+        descriptors.resize((layout.gdt_blocks * BLOCK_SIZE) as usize, 0);
+
+        let mut superblock = [0u8; 1024];
+        let put32 = |superblock: &mut [u8; 1024], at: usize, value: u32| {
+            superblock[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        let put16 = |superblock: &mut [u8; 1024], at: usize, value: u16| {
+            superblock[at..at + 2].copy_from_slice(&value.to_le_bytes());
+        };
+        let time = now();
+        put32(&mut superblock, 0, inodes_count as u32);
+        put32(&mut superblock, 4, layout.blocks_count as u32);
+        put32(&mut superblock, 12, free_blocks_total as u32);
+        put32(&mut superblock, 16, free_inodes_total as u32);
+        put32(&mut superblock, 20, 0); // first data block, 0 for blocks over 1K
+        put32(&mut superblock, 24, (BLOCK_SIZE / 1024).trailing_zeros());
+        put32(&mut superblock, 28, (BLOCK_SIZE / 1024).trailing_zeros());
+        put32(&mut superblock, 32, layout.blocks_per_group as u32);
+        put32(&mut superblock, 36, layout.blocks_per_group as u32);
+        put32(&mut superblock, 40, layout.inodes_per_group as u32);
+        put32(&mut superblock, 48, time);
+        put16(&mut superblock, 54, u16::MAX); // no forced checks
+        put16(&mut superblock, 56, EXT2_MAGIC);
+        put16(&mut superblock, 58, 1); // clean
+        put16(&mut superblock, 60, 1); // continue on errors
+        put32(&mut superblock, 64, time);
+        put32(&mut superblock, 76, 1); // dynamic revision
+        put32(&mut superblock, 84, LOST_FOUND_INO);
+        put16(&mut superblock, 88, INODE_SIZE as u16);
+        put32(&mut superblock, 96, FEATURE_INCOMPAT_FILETYPE);
+        put32(&mut superblock, 100, if large_file { FEATURE_RO_COMPAT_LARGE_FILE } else { 0 });
+        // uuid, unique enough to tell images apart
+        let seed = ((time as u64) << 32) ^ (std::process::id() as u64) ^ (offset << 7);
+        superblock[104..112].copy_from_slice(&seed.to_le_bytes());
+        superblock[112..120].copy_from_slice(&(!seed).rotate_left(17).to_le_bytes());
+
+        // every group carries a copy of the superblock and descriptors
+        for group in 0..layout.groups {
+            let start = layout.group_start(group);
+            put16(&mut superblock, 90, group as u16);
+            let mut block = vec![0u8; BLOCK_SIZE as usize];
+            // the primary superblock sits 1024 bytes into the device
+            let at = if group == 0 { 1024 } else { 0 };
+            block[at..at + 1024].copy_from_slice(&superblock);
+            write_block(file, start, &block)?;
+            write_block(file, start + 1, &descriptors)?;
+        }
+
+        let size = layout.blocks_count * BLOCK_SIZE;
+        file.flush()?;
+        Ok(size)
+    }
+}
+
+/// Write an indirect block of the given level for as many of `blocks` as it
+/// can address, consuming them. Returns the indirect block number.
+fn write_indirect(
+    file: &mut File,
+    offset: u64,
+    allocator: &mut Allocator,
+    level: u32,
+    blocks: &mut &[u32],
+) -> std::io::Result<u32> {
+    let block = allocator.alloc()?;
+    let mut pointers = Vec::with_capacity(POINTERS as usize);
+    while pointers.len() < POINTERS as usize && !blocks.is_empty() {
+        if level == 1 {
+            pointers.push(blocks[0]);
+            *blocks = &blocks[1..];
+        } else {
+            pointers.push(write_indirect(file, offset, allocator, level - 1, blocks)?);
+        }
+    }
+    let mut data = vec![0u8; BLOCK_SIZE as usize];
+    for (i, pointer) in pointers.iter().enumerate() {
+        data[i * 4..i * 4 + 4].copy_from_slice(&pointer.to_le_bytes());
+    }
+    file.seek(SeekFrom::Start(offset + block as u64 * BLOCK_SIZE))?;
+    file.write_all(&data)?;
+    Ok(block)
+}
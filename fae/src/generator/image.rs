@@ -1,7 +1,12 @@
+use std::io::Read;
 use std::process::Command;
 use std::path::{Path, PathBuf};
-use crate::utils::Arch;
+use std::sync::Mutex;
+use crate::error::{run, Error, Result};
+use crate::guard::{Guard, Resource};
+use crate::utils::{Arch, GuestNetwork};
 
+use super::manifest::{fixup_entries, injected_entries, EntryKind, ManifestEntry};
 use super::utils::{mkdir_p, Device, find_first_unused_nbd};
 
 fn merge_paths(base: &str, relative: &str) -> PathBuf {
@@ -16,259 +21,221 @@ fn merge_paths(base: &str, relative: &str) -> PathBuf {
     merged_path
 }
 
-fn resolve_link(path: &str) -> String {
-    let input_path = Path::new(path);
-    let parent_dir = input_path.parent().unwrap();
-
-    // 尝试读取符号链接
-    match std::fs::read_link(input_path) {
-        Ok(target_path) => {
-            return merge_paths(parent_dir.to_str().unwrap(),target_path.to_str().unwrap())
-                    .to_string_lossy().into_owned();
+const MIB: u64 = 1 << 20;
+/// Free space left for what the firmware writes at runtime, mostly under /tmp
+const RUNTIME_HEADROOM: u64 = 64 * MIB;
+/// Worst case metadata of one inode: its slot in the table, a directory
+/// entry and indirect blocks, rounded up to a whole block
+const INODE_COST: u64 = 4096;
+
+/// Held while an nbd device is picked and connected. It only serializes the
+/// generations of this process, a second cargo-fae may still pick the same
+/// device, and one of the two `qemu-nbd -c` then fails.
+static NBD_LOCK: Mutex<()> = Mutex::new(());
+
+/// Bytes the rootfs plus the files of `entries` take in whole 4K blocks,
+/// and the number of inodes they need
+pub fn content_size(rootfs: &Path, entries: &[ManifestEntry]) -> (u64, u64) {
+    let blocks = |len: u64| len.div_ceil(4096) * 4096;
+    let mut bytes = 0;
+    let mut inodes = 0;
+    let mut stack = vec![rootfs.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let Ok(children) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for child in children.flatten() {
+            let Ok(metadata) = child.path().symlink_metadata() else {
+                continue;
+            };
+            inodes += 1;
+            if metadata.is_dir() {
+                stack.push(child.path());
+            } else if metadata.is_file() {
+                bytes += blocks(metadata.len());
+            }
         }
-        Err(_) => {
-            // 如果不是符号链接，返回输入路径
-            return input_path.to_string_lossy().into_owned();
+    }
+    for entry in entries {
+        inodes += 1;
+        match &entry.kind {
+            EntryKind::File { source } => bytes += std::fs::metadata(source).map_or(0, |metadata| blocks(metadata.len())),
+            EntryKind::Text { content } => bytes += blocks(content.len() as u64),
+            _ => {}
         }
     }
+    (bytes, inodes)
+}
+
+/// Smallest image the content fits in, partition offset included
+pub fn minimum_image_size(rootfs: &Path, entries: &[ManifestEntry]) -> u64 {
+    let (bytes, inodes) = content_size(rootfs, entries);
+    (bytes + inodes * INODE_COST + MIB).div_ceil(MIB) * MIB
 }
 
-pub fn create_image(image_type_str: &str, image: &str) {
-    let output =  Command::new("qemu-img")
+/// Size of the image for `rootfs`: the content, a quarter on top for
+/// filesystem overhead and the runtime headroom, in whole MiB
+pub fn image_size(rootfs: &Path, entries: &[ManifestEntry]) -> u64 {
+    let minimum = minimum_image_size(rootfs, entries);
+    (minimum + minimum / 4 + RUNTIME_HEADROOM).div_ceil(MIB) * MIB
+}
+
+pub fn create_image(image_type_str: &str, image: &str, size: u64) -> Result<()> {
+    run(Command::new("qemu-img")
             .arg("create")
             .arg("-f")
             .arg(image_type_str)
             .arg(image)
-            .arg("1G")
-            .output();
-    match output {
-        Ok(output) if output.status.success() => {
-            println!("Successfully created image: {}", image);
-        }
-        Ok(output) => {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Failed to create image: {}", error_message);
-            std::process::exit(1);
-        }
-        Err(err) => {
-            eprintln!("Command execution failed: {}", err);
-            std::process::exit(1);
-        }
-    };
+            .arg(size.to_string()))?;
+    println!("Successfully created image: {}", image);
+    Ok(())
 }
 
-pub fn create_mount_point(image: &str) -> String {
+pub fn create_mount_point(image: &str) -> Result<String> {
     let image_path = Path::new(image);
-    let mount_point_path = image_path.parent().unwrap().join("temp_image");
+    // one per image, images in the same directory may be built at once
+    let file_name = image_path.file_name().map_or("image".into(), |name| name.to_string_lossy());
+    let mount_point_path = image_path.parent().unwrap().join(format!("temp_image_{}", file_name));
     let mount_point = mount_point_path.as_os_str().to_str().unwrap();
-    if let Err(err) = std::fs::create_dir_all(&mount_point) {
-        eprintln!("create mount point failed: {}", err);
-        std::process::exit(1);
-    } else {
-        println!("mount_point: {}", mount_point);
-    }
-    mount_point.to_string()
+    std::fs::create_dir_all(mount_point)
+        .map_err(|err| Error::io(format!("create mount point {} failed", mount_point), err))?;
+    println!("mount_point: {}", mount_point);
+    Ok(mount_point.to_string())
 }
 
-pub fn mount_qcow2_image(image: &str, mount_point: &str) -> String {
-    let output = Command::new("sudo")
-                                    .args(&["modprobe", "nbd"]).output();
-    match output {
-        Ok(output) if output.status.success() => {
-            println!("Successfully loaded nbd module.");
-        }
-        Ok(output) => {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Failed to load nbd module: {}", error_message);
-            std::process::exit(1);
-        }
-        Err(err) => {
-            eprintln!("Command execution failed: {}", err);
-            std::process::exit(1);
-        }
+/// A formatted image mounted on the host. Dropping it unmounts the
+/// filesystem first, then disconnects the device under it.
+pub struct MountedImage {
+    pub mount: Guard,
+    pub device: Guard,
+}
+
+impl MountedImage {
+    pub fn release(self) -> Result<()> {
+        self.mount.release()?;
+        self.device.release()
     }
+}
 
-    let nbd_device = find_first_unused_nbd()
-        .expect("Failed to find any unused nbd device!");
-    println!("nbd_device: {}", &nbd_device);
+pub fn mount_qcow2_image(image: &str, mount_point: &str) -> Result<MountedImage> {
+    run(Command::new("sudo").args(["modprobe", "nbd"]))?;
+    println!("Successfully loaded nbd module.");
+
+    // a device only shows as used once connected, so pick and connect one
+    // generation at a time
+    let (device, nbd_device) = {
+        let _lock = NBD_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let nbd_device = find_first_unused_nbd()
+            .ok_or_else(|| Error::Invalid("Failed to find any unused nbd device!".to_string()))?;
+        println!("nbd_device: {}", &nbd_device);
+
+        // Connect image with an nbd device
+        run(Command::new("sudo").args(["qemu-nbd", "-c", &nbd_device, image]))?;
+        println!("Successfully connect image with nbd device: {}", &nbd_device);
+        (Guard::new(Resource::Nbd(nbd_device.clone())), nbd_device)
+    };
 
-    // Connect image with an nbd device
-    let output = Command::new("sudo")
-        .args(&["qemu-nbd", "-c", &nbd_device, image])
-        .output();
-    match output {
-        Ok(output) if output.status.success() => {
-            println!("Successfully connect image with nbd device: {}", &nbd_device)
-        }
-        Ok(output) => {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Failed to connect image with nbd device: {}", error_message);
-            std::process::exit(1);
-        }
-        Err(err) => {
-            eprintln!("Command execution failed: {}", err);
-            std::process::exit(1);
-        }
-    }
+    partition_disk(&nbd_device)?;
+    let partition = format!("{}p1", &nbd_device);
+    make_ext2(&partition)?;
+    let mount = mount_device(&partition, mount_point)?;
+
+    Ok(MountedImage { mount, device })
+}
 
-    // Create partition for nbd device, must be bash not sh
+/// Create a single primary partition covering the whole disk.
+/// `disk` may be a block device or a raw image file.
+pub fn partition_disk(disk: &str) -> Result<()> {
+    // Create partition for the disk, must be bash not sh
     // must use dos rather than gpt partition
-    let output = Command::new("bash")
+    run(Command::new("bash")
         .arg("-c")
-        .arg(format!("echo -e 'o\\nn\\np\\n1\\n\\n\\nw' | sudo fdisk {}", &nbd_device))
-        .output();
-    match output {
-        Ok(output) if output.status.success() => {
-            println!("Successfully created partition for nbd device.");
-        }
-        Ok(output) => {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Failed to create partition for nbd device: {}", error_message);
-            std::process::exit(1);
-        }
-        Err(err) => {
-            eprintln!("Command execution failed: {}", err);
-            std::process::exit(1);
-        }
-    }
-    
-    // Make file system for partition 1 of the nbd device
-    // better to use ext2, ext4 may fail when boot with qemu
-    let output = Command::new("sudo")
-        .args(&["mkfs.ext2", &format!("{}p1", &nbd_device)])
-        .output();
-    match output {
-        Ok(output) if output.status.success() => {
-            println!("Successfully mkfs.ext4 for {}p1", &nbd_device);
-        }
-        Ok(output) => {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Failed to make file system for partition 1 of the nbd device: {}", error_message);
-            std::process::exit(1);
-        }
-        Err(err) => {
-            eprintln!("Command execution failed: {}", err);
-            std::process::exit(1);
-        }
-    }
-
-    // Mount device to mount_point
-    let output = Command::new("sudo")
-        .args(&["mount", &format!("{}p1", &nbd_device), mount_point])
-        .output();
-    match output {
-        Ok(output) if output.status.success() => {
-            println!("Successfully mounted device to: {}", mount_point);
-        }
-        Ok(output) => {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Failed to mount device to {}: {}", mount_point, error_message);
-            std::process::exit(1);
-        }
-        Err(err) => {
-            eprintln!("Command execution failed: {}", err);
-            std::process::exit(1);
-        }
-    }
-
-    nbd_device
+        .arg(format!("echo -e 'o\\nn\\np\\n1\\n\\n\\nw' | sudo fdisk {}", disk)))?;
+    println!("Successfully created partition for {}.", disk);
+    Ok(())
 }
 
-pub fn mount_raw_image(image: &str, mount_point: &str) -> String {
-    let output = Command::new("sudo")
-                .args(&["mount", "-o", "loop", image, mount_point])
-                .output();
+pub fn make_ext2(partition: &str) -> Result<()> {
+    // Make file system for the partition
+    // better to use ext2, ext4 may fail when boot with qemu
+    // one inode per 4K, the image is sized for that many (see INODE_COST)
+    run(Command::new("sudo").args(["mkfs.ext2", "-q", "-i", "4096", partition]))?;
+    println!("Successfully mkfs.ext2 for {}", partition);
+    Ok(())
+}
 
-    match output {
-        Ok(output) if output.status.success() => {
-            println!("Successfully mounted image: {}", image);
-        }
-        Ok(output) => {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Failed to mount image: {}", error_message);
-            std::process::exit(1);
-        }
-        Err(err) => {
-            eprintln!("Command execution failed: {}", err);
-            std::process::exit(1);
-        }
-    }
-    "todo".to_string()
+pub fn mount_device(device: &str, mount_point: &str) -> Result<Guard> {
+    run(Command::new("sudo").args(["mount", device, mount_point]))?;
+    println!("Successfully mounted device to: {}", mount_point);
+    Ok(Guard::new(Resource::Mount(mount_point.to_string())))
 }
 
-pub fn fix_image(mount_point: &str) {
-    let dirs = [
-            "/proc", "/dev/pts", "/etc_ro", "/tmp", "/var", "/run",
-            "/sys", "/root", "/tmp/var", "/tmp/media", "/tmp/etc",
-            "/tmp/var/run", "/tmp/home/root", "/tmp/mnt", "/tmp/opt",
-            "/tmp/www", "/var/run", "/var/lock", "/usr/bin", "/usr/sbin",
-            "/dev/mtd", "/dev/tts", "/dev/mtdblock"
-        ];
-    for dir in &dirs {
-        mkdir_p(&resolve_link(merge_paths(mount_point, &dir).to_str().unwrap()));
+/// Byte offset and length of the first partition, read from the MBR of `image`
+pub fn first_partition(image: &str) -> Option<(u64, u64)> {
+    let mut mbr = [0u8; 512];
+    std::fs::File::open(image).ok()?.read_exact(&mut mbr).ok()?;
+    if mbr[510..512] != [0x55, 0xaa] {
+        return None;
     }
-    
-    let devices = [
-        ("/dev/mem", "c", 660, (1, 1)),
-        ("/dev/kmem", "c", 640, (1, 2)),
-        ("/dev/null", "c", 666, (1, 3)),
-        ("/dev/zero", "c", 666, (1, 5)),
-        ("/dev/random", "c", 444, (1, 8)),
-        ("/dev/urandom", "c", 444, (1, 9)),
-        ("/dev/armem", "c", 666, (1, 13)),
-
-        ("/dev/tty", "c", 666, (5, 0)),
-        ("/dev/console", "c", 622, (5, 1)),
-        ("/dev/ptmx", "c", 666, (5, 2)),
-
-        ("/dev/tty0", "c", 622, (4, 0)),
-        ("/dev/ttyS0", "c", 660, (4, 64)),
-        ("/dev/ttyS1", "c", 660, (4, 65)),
-        ("/dev/ttyS2", "c", 660, (4, 67)),
-        ("/dev/ttyS3", "c", 660, (4, 66)),
-
-        ("/dev/tts/0", "c", 660, (4, 64)),
-        ("/dev/tts/1", "c", 660, (4, 65)),
-        ("/dev/tts/2", "c", 660, (4, 66)),
-        ("/dev/tts/3", "c", 660, (4, 67)),
-    ];
-    for (device_name, device_type, mode, rdev) in devices {
-        let real_device_name = merge_paths(mount_point, device_name);
-        Device::new(real_device_name.to_str().unwrap(), device_type, mode, rdev).create();
+    // first entry of the partition table
+    let entry = &mbr[446..462];
+    let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+    let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+    if sectors == 0 {
+        return None;
     }
+    Some((start * 512, sectors * 512))
+}
 
-    for i in 0..=10 {
-        let real_device_name = merge_paths(mount_point, &format!("/dev/mtdblock/{}", i));
-        Device::new(real_device_name.to_str().unwrap(), "b", 644, (31, i)).create();
-    }
+/// Partition a raw image, attach its first partition to a loop device,
+/// format and mount it
+pub fn mount_raw_image(image: &str, mount_point: &str) -> Result<MountedImage> {
+    partition_disk(image)?;
+    let (offset, size) = first_partition(image)
+        .ok_or_else(|| Error::parse(format!("the partition table of {}", image), "no valid first partition"))?;
 
-    for i in 0..=10 {
-        let real_device_name = merge_paths(mount_point, &format!("/dev/mtdblock{}", i));
-        Device::new(real_device_name.to_str().unwrap(), "b", 644, (31, i)).create();
-    }
+    let output = run(Command::new("sudo")
+        .args(["losetup", "--find", "--show",
+            "--offset", &offset.to_string(), "--sizelimit", &size.to_string(), image]))?;
+    let loop_device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    println!("Successfully attached {} at offset {} to {}", image, offset, loop_device);
+    let device = Guard::new(Resource::Loop(loop_device.clone()));
+
+    make_ext2(&loop_device)?;
+    let mount = mount_device(&loop_device, mount_point)?;
 
-    // [TODO] 创建设备节点 /dev/null /dev/mtd* /dev/mtdblock* /dev/gpio
+    Ok(MountedImage { mount, device })
 }
 
-pub fn enhance_image(mount_point: &str, arch: &Arch) {
-    let arch_str= arch.to_str();
-    let binaries_base = "../binaries";
-    let binaries = [
-        (format!("{}/agent/agent.{}", binaries_base, arch_str), "agent"),
-        (format!("{}/busybox/busybox.{}", binaries_base, arch_str), "busybox"),
-        (format!("{}/preInit/preInit.sh", binaries_base), "preInit.sh"),
-    ];
+pub fn fix_image(mount_point: &str) -> Result<()> {
+    apply_manifest(mount_point, &fixup_entries(Path::new(mount_point)))
+}
 
-    for (binary, dest) in &binaries {
-        let output = Command::new("sudo")
-            .args(&["cp", binary, merge_paths(mount_point, dest).to_str().unwrap()])
-            .output()
-            .expect("Failed to execute command: cp");
+pub fn enhance_image(mount_point: &str, arch: &Arch, network: &GuestNetwork) -> Result<()> {
+    apply_manifest(mount_point, &injected_entries(arch, network)?)
+}
 
-        if !output.status.success() {
-            println!("cp error: {}", String::from_utf8_lossy(&output.stderr));
-            std::process::exit(1);
+/// Create the manifest entries inside the mounted image with sudo
+pub fn apply_manifest(mount_point: &str, entries: &[ManifestEntry]) -> Result<()> {
+    for entry in entries {
+        let real_path = merge_paths(mount_point, &entry.path);
+        let real_path = real_path.to_str().unwrap();
+        match &entry.kind {
+            EntryKind::Directory => mkdir_p(real_path)?,
+            EntryKind::CharDevice { major, minor } | EntryKind::BlockDevice { major, minor } => {
+                let device_type = if let EntryKind::CharDevice { .. } = entry.kind { "c" } else { "b" };
+                // a node the firmware already ships is fine, keep going
+                if let Err(err) = Device::new(real_path, device_type, entry.mode, (*major, *minor)).create() {
+                    eprintln!("{}", err);
+                }
+            }
+            EntryKind::File { source } => {
+                run(Command::new("sudo").arg("cp").arg(source).arg(real_path))?;
+            }
+            EntryKind::Text { content } => {
+                run(Command::new("sudo").args(["sh", "-c", "printf '%s' \"$1\" > \"$2\"", "sh", content, real_path]))?;
+            }
         }
     }
-    
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::assets::Assets;
+use crate::error::{Error, Result};
+use crate::utils::{Arch, GuestNetwork};
+
+/// What a manifest entry puts into the image
+#[derive(Clone, Debug)]
+pub enum EntryKind {
+    Directory,
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+    /// Regular file copied from the host
+    File { source: PathBuf },
+    /// Regular file holding `content`
+    Text { content: String },
+}
+
+/// One path the generator adds on top of the extracted rootfs, with the
+/// ownership and permissions it must have inside the guest
+#[derive(Clone, Debug)]
+pub struct ManifestEntry {
+    /// Absolute path inside the guest
+    pub path: String,
+    pub kind: EntryKind,
+    /// Permission bits, without the file type
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl ManifestEntry {
+    fn new(path: &str, kind: EntryKind, mode: u32) -> Self {
+        ManifestEntry { path: path.to_string(), kind, mode, uid: 0, gid: 0 }
+    }
+}
+
+/// Directories the firmware expects to find at boot
+const DIRS: [&str; 23] = [
+    "/proc", "/dev/pts", "/etc_ro", "/tmp", "/var", "/run",
+    "/sys", "/root", "/tmp/var", "/tmp/media", "/tmp/etc",
+    "/tmp/var/run", "/tmp/home/root", "/tmp/mnt", "/tmp/opt",
+    "/tmp/www", "/var/run", "/var/lock", "/usr/bin", "/usr/sbin",
+    "/dev/mtd", "/dev/tts", "/dev/mtdblock"
+];
+
+/// Character devices as (path, mode, (major, minor))
+const CHAR_DEVICES: [(&str, u32, (u32, u32)); 19] = [
+    ("/dev/mem", 0o660, (1, 1)),
+    ("/dev/kmem", 0o640, (1, 2)),
+    ("/dev/null", 0o666, (1, 3)),
+    ("/dev/zero", 0o666, (1, 5)),
+    ("/dev/random", 0o444, (1, 8)),
+    ("/dev/urandom", 0o444, (1, 9)),
+    ("/dev/armem", 0o666, (1, 13)),
+
+    ("/dev/tty", 0o666, (5, 0)),
+    ("/dev/console", 0o622, (5, 1)),
+    ("/dev/ptmx", 0o666, (5, 2)),
+
+    ("/dev/tty0", 0o622, (4, 0)),
+    ("/dev/ttyS0", 0o660, (4, 64)),
+    ("/dev/ttyS1", 0o660, (4, 65)),
+    ("/dev/ttyS2", 0o660, (4, 67)),
+    ("/dev/ttyS3", 0o660, (4, 66)),
+
+    ("/dev/tts/0", 0o660, (4, 64)),
+    ("/dev/tts/1", 0o660, (4, 65)),
+    ("/dev/tts/2", 0o660, (4, 66)),
+    ("/dev/tts/3", 0o660, (4, 67)),
+];
+
+/// Resolve a guest directory through a symlink at its last component,
+/// e.g. `/var -> /tmp/var` in many firmwares
+fn resolve_guest_dir(root: &Path, dir: &str) -> String {
+    let host_path = root.join(dir.trim_start_matches('/'));
+    match std::fs::read_link(&host_path) {
+        Ok(target) if target.has_root() => target.to_string_lossy().into_owned(),
+        Ok(target) => Path::new(dir).parent().unwrap().join(target).to_string_lossy().into_owned(),
+        Err(_) => dir.to_string(),
+    }
+}
+
+/// Directories and device nodes the firmware needs, resolved against `root`
+/// (the extracted rootfs or the mounted image holding a copy of it)
+pub fn fixup_entries(root: &Path) -> Vec<ManifestEntry> {
+    let mut entries: Vec<ManifestEntry> = DIRS
+        .iter()
+        .map(|dir| ManifestEntry::new(&resolve_guest_dir(root, dir), EntryKind::Directory, 0o755))
+        .collect();
+
+    for (path, mode, (major, minor)) in CHAR_DEVICES {
+        entries.push(ManifestEntry::new(path, EntryKind::CharDevice { major, minor }, mode));
+    }
+    for i in 0..=10 {
+        let kind = EntryKind::BlockDevice { major: 31, minor: i };
+        entries.push(ManifestEntry::new(&format!("/dev/mtdblock/{}", i), kind.clone(), 0o644));
+        entries.push(ManifestEntry::new(&format!("/dev/mtdblock{}", i), kind, 0o644));
+    }
+    // [TODO] 创建设备节点 /dev/gpio
+    entries
+}
+
+/// Our own tools copied into the root of the image, all but the agent have
+/// to be in the assets for `arch`, and the guest network script with its
+/// settings under `/firmadyne`. Nothing at boot needs the agent, images of
+/// an arch without one go without it.
+pub fn injected_entries(arch: &Arch, network: &GuestNetwork) -> Result<Vec<ManifestEntry>> {
+    let assets = Assets::get()?;
+    let mut binaries = vec![
+        (assets.busybox(arch), "/busybox"),
+        (assets.pre_init(), "/preInit.sh"),
+        (assets.network_script(), "/firmadyne/network.sh"),
+    ];
+    if assets.agent(arch).is_file() {
+        binaries.insert(0, (assets.agent(arch), "/agent"));
+    }
+
+    let mut entries = vec![ManifestEntry::new("/firmadyne", EntryKind::Directory, 0o755)];
+    for (source, dest) in binaries {
+        // keep the permissions of the shipped binary, like cp does
+        let mode = std::fs::metadata(&source)
+            .map(|metadata| metadata.permissions().mode() & 0o7777)
+            .map_err(|err| Error::io(format!("No {} for {} in the assets, {}", dest, arch.to_str(), source.display()), err))?;
+        entries.push(ManifestEntry::new(dest, EntryKind::File { source }, mode));
+    }
+    let settings = [
+        ("/firmadyne/network_type", network.network_type.to_str()),
+        ("/firmadyne/net_bridge", &network.bridge),
+        ("/firmadyne/net_interface", &network.interface),
+    ];
+    for (path, value) in settings {
+        entries.push(ManifestEntry::new(path, EntryKind::Text { content: format!("{}\n", value) }, 0o644));
+    }
+    Ok(entries)
+}
@@ -1,73 +1,94 @@
-use std::process::{Command, exit};
+use std::process::Command;
 
 pub mod utils;
+mod ext2;
 mod image;
+mod manifest;
+mod rootless;
 use utils::*;
-use crate::utils::Arch;
+use crate::assets::Assets;
+use crate::error::{Error, Result};
+use crate::utils::{Arch, GuestNetwork, ImageInfo};
 use crate::ImageType;
+use crate::detector::detect_arch;
 use image::*;
+use rootless::generate_image_rootless;
 
 
-pub fn generate_image(rootfs: &str, image: &str, image_type: &ImageType, arch: &Arch) {
+/// Build `image` from `rootfs`. With `rootless` set, no sudo, nbd or mount
+/// is needed and the filesystem is assembled in user space. Without `size`
+/// the image is sized from the rootfs. Devices and mounts set up on the way
+/// are released even when a step fails. `network` is written into the image
+/// for the guest network script. Returns the architecture the image was
+/// built for.
+pub fn generate_image(rootfs: &str, image: &str, image_type: &ImageType, arch: &Option<Arch>, rootless: bool, size: Option<u64>,
+                      network: &GuestNetwork) -> Result<Arch> {
+    let arch = match arch {
+        Some(arch) => arch.clone(),
+        None => detect_arch(std::path::Path::new(rootfs)).ok_or_else(|| {
+            Error::Invalid(format!("Failed to detect the architecture of {}, please specify it with --arch", rootfs))
+        })?,
+    };
+
     // let image_path = get_unique_file_name(image);
     // let image = image_path.to_str().unwrap();
 
-    // load nbd module
-    if Command::new("sudo").args(&["modprobe", "nbd"]).output().is_err() {
-        eprintln!("modprobe is not installed. Please install it and try again.");
-        exit(1);
+    println!("image: {}", image);
+    if !Assets::get()?.agent(&arch).is_file() {
+        println!("No agent for {} in the assets, the image goes without one", arch.to_str());
     }
 
-    println!("image: {}", image);
-    
+    let mut entries = manifest::fixup_entries(std::path::Path::new(rootfs));
+    entries.extend(manifest::injected_entries(&arch, network)?);
+    let minimum = minimum_image_size(std::path::Path::new(rootfs), &entries);
+    let size = match size {
+        Some(size) if size < minimum => {
+            return Err(Error::Invalid(format!("Image size {} MiB is too small for {}, it needs at least {} MiB",
+                size >> 20, rootfs, minimum.div_ceil(1 << 20))));
+        }
+        Some(size) => size,
+        None => image_size(std::path::Path::new(rootfs), &entries),
+    };
+    println!("image size: {} MiB", size >> 20);
 
-    // Check if binwalk is installed
-    if Command::new("qemu-img").arg("-h").output().is_err() {
-        eprintln!("qemu-img is not installed. Please install it and try again.");
-        exit(1);
+    let info = ImageInfo { arch: Some(arch.clone()), guest_network: Some(network.clone()), ..ImageInfo::default() };
+    if rootless {
+        generate_image_rootless(rootfs, image, image_type, &arch, size, network)?;
+        info.save(image)?;
+        return Ok(arch);
     }
 
-    // get image type
-    let image_type_str = match image_type {
-        ImageType::Qcow2 => "qcow2",
-        ImageType::Raw => "raw",
-    };
+    // Check if qemu-img is installed
+    if Command::new("qemu-img").arg("-h").output().is_err() {
+        return Err(Error::MissingTool { tool: "qemu-img".to_string() });
+    }
 
     // create image with qemu-img
-    create_image(image_type_str, image);
+    create_image(image_type.to_str(), image, size)?;
 
     // create mount point
-    let mount_point_string = create_mount_point(image);
+    let mount_point_string = create_mount_point(image)?;
     let mount_point = mount_point_string.as_str();
 
-    let immediate_device = match image_type {
+    let mounted = match image_type {
         ImageType::Qcow2 => {
             // mount the qcow2 image
-            mount_qcow2_image(image, &mount_point)
+            mount_qcow2_image(image, mount_point)?
         }
         ImageType::Raw => {
-            // [TODO] mount the raw image
-            mount_raw_image(image, mount_point)
+            // partition the raw image and attach it to a loop device
+            mount_raw_image(image, mount_point)?
         }
     };
 
     // Copy files into the mounted image
-    copy_dir_recursive(rootfs, mount_point);
-    fix_image(mount_point);
-    enhance_image(mount_point, arch);
+    copy_dir_recursive(rootfs, mount_point)?;
+    fix_image(mount_point)?;
+    enhance_image(mount_point, &arch, network)?;
 
-    // umount mount_point
-    umount(mount_point);
-
-    match image_type {
-        ImageType::Qcow2 => {
-            // disconnect nbd device
-            disconnect_nbd_device(&immediate_device);
-        }
-        ImageType::Raw => {
-            // [TODO] disconnect raw device
-        }
-        
-    }
+    // umount mount_point, then disconnect the nbd or loop device
+    mounted.release()?;
 
-}
\ No newline at end of file
+    info.save(image)?;
+    Ok(arch)
+}
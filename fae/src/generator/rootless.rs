@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::{run, Error, Result};
+use crate::utils::{Arch, GuestNetwork};
+use crate::ImageType;
+
+use super::ext2::{Ext2Reader, Ext2Writer, FileData, FileTree, Node, NodeKind};
+use super::manifest::{fixup_entries, injected_entries, EntryKind, ManifestEntry};
+
+/// Sector the partition starts at, the same as fdisk's default
+pub const PARTITION_START_SECTOR: u64 = 2048;
+const SECTOR_SIZE: u64 = 512;
+
+/// Build the image without root: the rootfs and the manifest entries are
+/// laid out as an ext2 filesystem in user space and the partition table is
+/// written by us
+pub fn generate_image_rootless(rootfs: &str, image: &str, image_type: &ImageType, arch: &Arch, size: u64,
+                               network: &GuestNetwork) -> Result<()> {
+    let disk_image = match image_type {
+        ImageType::Raw => image.to_string(),
+        ImageType::Qcow2 => format!("{}.raw.tmp", image),
+    };
+
+    let mut tree = FileTree::from_dir(Path::new(rootfs))
+        .map_err(|err| Error::io(format!("Failed to read rootfs {}", rootfs), err))?;
+    // `sudo cp -r` leaves every copied file owned by root
+    tree.root.for_each_mut(&mut |node| {
+        node.uid = 0;
+        node.gid = 0;
+    });
+    // the writer adds one anyway, keeping it in the tree lets write_disk
+    // compare node counts after reading the image back
+    tree.create_dir_all("/lost+found", 0o700);
+
+    let mut entries = fixup_entries(Path::new(rootfs));
+    entries.extend(injected_entries(arch, network)?);
+    apply_manifest_tree(&mut tree, &entries)?;
+
+    write_disk(&disk_image, &tree, size)?;
+
+    if let ImageType::Qcow2 = image_type {
+        let result = convert_image(&disk_image, image);
+        let _ = std::fs::remove_file(&disk_image);
+        result?;
+    }
+    println!("Successfully generated image without root: {}", image);
+    Ok(())
+}
+
+/// Apply the manifest to the in-memory tree, the same way `apply_manifest`
+/// does on a mounted image
+pub fn apply_manifest_tree(tree: &mut FileTree, entries: &[ManifestEntry]) -> Result<()> {
+    for entry in entries {
+        let kind = match &entry.kind {
+            EntryKind::Directory => {
+                tree.create_dir_all(&entry.path, entry.mode);
+                continue;
+            }
+            EntryKind::CharDevice { major, minor } => NodeKind::CharDevice { major: *major, minor: *minor },
+            EntryKind::BlockDevice { major, minor } => NodeKind::BlockDevice { major: *major, minor: *minor },
+            EntryKind::File { source } => {
+                let metadata = std::fs::metadata(source)
+                    .map_err(|err| Error::io(format!("Failed to read {}", source.display()), err))?;
+                NodeKind::File(FileData::Host { path: source.clone(), size: metadata.len() })
+            }
+            EntryKind::Text { content } => NodeKind::File(FileData::Bytes(content.clone().into_bytes())),
+        };
+        let mut node = Node::new(kind, entry.mode);
+        node.uid = entry.uid;
+        node.gid = entry.gid;
+        tree.insert(&entry.path, node);
+    }
+    Ok(())
+}
+
+/// A DOS partition table with one Linux partition starting at
+/// `start_sector` and spanning `sectors`
+pub fn mbr(start_sector: u32, sectors: u32) -> [u8; 512] {
+    let mut mbr = [0u8; 512];
+    // disk identifier, the kernel does not care about its value
+    mbr[440..444].copy_from_slice(&0x4641_4500u32.to_le_bytes());
+    let entry = &mut mbr[446..462];
+    // not bootable, CHS fields maxed out so only the LBA values are used
+    entry[0] = 0x00;
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = 0x83;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&start_sector.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    mbr
+}
+
+/// Lay out a partitioned raw disk of `size` bytes holding `tree` as an ext2
+/// filesystem in its first partition. The file is sparse, only used blocks
+/// are written.
+pub fn write_disk(disk_image: &str, tree: &FileTree, size: u64) -> Result<()> {
+    let result = (|| -> std::io::Result<()> {
+        let start = PARTITION_START_SECTOR * SECTOR_SIZE;
+        let sectors = (size - start) / SECTOR_SIZE;
+
+        let mut disk = File::create(disk_image)?;
+        disk.set_len(size)?;
+        disk.write_all(&mbr(PARTITION_START_SECTOR as u32, sectors as u32))?;
+        Ext2Writer::new(tree).size(size - start).write(&mut disk, start)?;
+
+        // read it back to make sure nothing got lost on the way
+        let written = Ext2Reader::open(File::open(disk_image)?, start)?.read_tree()?;
+        if written.root.count() != tree.root.count() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} nodes written but {} read back", tree.root.count(), written.root.count()),
+            ));
+        }
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => {
+            println!("Successfully wrote disk {}", disk_image);
+            Ok(())
+        }
+        Err(err) => Err(Error::io(format!("Failed to write disk {}", disk_image), err)),
+    }
+}
+
+pub fn convert_image(raw_image: &str, qcow2_image: &str) -> Result<()> {
+    run(Command::new("qemu-img").args(["convert", "-f", "raw", "-O", "qcow2", raw_image, qcow2_image]))?;
+    println!("Successfully converted {} to {}", raw_image, qcow2_image);
+    Ok(())
+}
@@ -1,31 +1,20 @@
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use crate::error::{run, Error, Result};
+use crate::guard::Resource;
+
 /// For file
-pub fn copy_dir_recursive(src: &str, dst: &str) {
+pub fn copy_dir_recursive(src: &str, dst: &str) -> Result<()> {
     let mut src_path = Path::new(src).to_path_buf();
     src_path.push("*");
     let wild_src = src_path.as_os_str().to_str().unwrap();
 
-    let output = Command::new("sudo")
-                .args(&["bash", "-c"])
-                .arg(&format!("cp -r {} {}", wild_src, dst))
-                .output();
-
-    match output {
-        Ok(output) if output.status.success() => {
-            println!("Successfully copy dir from {}  to: {}", src, dst);
-        }
-        Ok(output) => {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Failed copy dir: {}", error_message);
-            std::process::exit(1);
-        }
-        Err(err) => {
-            eprintln!("Command execution failed: {}", err);
-            std::process::exit(1);
-        }
-    }
+    run(Command::new("sudo")
+                .args(["bash", "-c"])
+                .arg(format!("cp -r {} {}", wild_src, dst)))?;
+    println!("Successfully copy dir from {}  to: {}", src, dst);
+    Ok(())
 }
 
 #[allow(dead_code)]
@@ -39,7 +28,7 @@ pub fn get_unique_file_name(image: &str) -> PathBuf  {
     // Create a new base name without the extension
     let base_name = original_path
         .file_stem()
-        .unwrap_or_else(|| original_path.as_os_str())
+        .unwrap_or(original_path.as_os_str())
         .to_str()
         .unwrap_or("");
 
@@ -61,15 +50,12 @@ pub fn get_unique_file_name(image: &str) -> PathBuf  {
 /// For nbd device
 pub fn find_first_unused_nbd() -> Option<String>{
     // List all NBD devices
-    let all_nbd_devices = get_all_nbds().unwrap();
-    let active_nbd_devices = get_active_nbds().unwrap();
+    let all_nbd_devices = get_all_nbds()?;
+    let active_nbd_devices = get_active_nbds()?;
     // Identify and return the first unused NBD device
-    for device in all_nbd_devices {
-        if !active_nbd_devices.contains(&device) {
-            return Some(device);
-        }
-    }
-    None
+    all_nbd_devices
+        .into_iter()
+        .find(|device| !active_nbd_devices.contains(device))
 }
 
 pub fn get_all_nbds() -> Option<Vec<String>> {
@@ -106,7 +92,7 @@ pub fn get_active_nbds() -> Option<Vec<String>> {
         .filter_map(|s| {
             let name = s.trim();
             if name.starts_with("nbd") {
-                Some(format!("{}", name))
+                Some(name.to_string())
             } else {
                 None
             }
@@ -118,8 +104,8 @@ pub fn get_active_nbds() -> Option<Vec<String>> {
 
 #[allow(dead_code)]
 pub fn get_unused_nbds() -> Option<Vec<String>> {
-    let all_nbd_devices = get_all_nbds().unwrap();
-    let active_nbd_devices = get_active_nbds().unwrap();
+    let all_nbd_devices = get_all_nbds()?;
+    let active_nbd_devices = get_active_nbds()?;
     
     let unused_ndb_devices = all_nbd_devices
         .iter()
@@ -130,39 +116,17 @@ pub fn get_unused_nbds() -> Option<Vec<String>> {
     Some(unused_ndb_devices)
 }
 
-pub fn umount(mount_point: &str) {
-    let output = Command::new("sudo")
-            .args(&["umount", mount_point])
-            .output()
-            .expect("Failed to execute command: umount");
-
-    if !output.status.success() {
-        eprintln!("Failed to umount: {}", String::from_utf8_lossy(&output.stderr));
-        std::process::exit(1);
-    } else {
-        println!("umount: {}", mount_point);
-    }
-}
-
-pub fn mkdir_p(directory: &str) {
-    let output = Command::new("sudo")
-            .args(&["mkdir", "-p", directory])
-            .output()
-            .expect("Failed to execute command: mkdir");
-
-    if !output.status.success() {
-        eprintln!("Failed to mkdir: {}", String::from_utf8_lossy(&output.stderr));
-        std::process::exit(1);
-    } else {
-        println!("mkdir: {}", directory);
-    }
+pub fn mkdir_p(directory: &str) -> Result<()> {
+    run(Command::new("sudo").args(["mkdir", "-p", directory]))?;
+    println!("mkdir: {}", directory);
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct Device {
     name: String,
     device_type: String, // c or b
-    mode: u32,  // 权限模式
+    mode: u32,  // 权限模式, e.g. 0o660
     rdev: (u32, u32), // 主设备号和次设备号
 }
 
@@ -176,48 +140,28 @@ impl Device {
         }
     }
 
-    pub fn create(&self) {
+    pub fn create(&self) -> Result<()> {
         // 使用 mknod 创建设备节点
-        let output = Command::new("sudo")
+        run(Command::new("sudo")
             .arg("mknod")
-            .arg("-m").arg(self.mode.to_string())
+            .arg("-m").arg(format!("{:o}", self.mode))
             .arg(&self.name)
             .arg(&self.device_type)
             .arg(self.rdev.0.to_string())
-            .arg(self.rdev.1.to_string())
-            .output()
-            .expect("Failed to create device node");
-
-        if !output.status.success() {
-            eprintln!("mknod {}: {}", self.name, String::from_utf8_lossy(&output.stderr));
-        } else {
-            println!("mknod: {}", self.name);
-        }
+            .arg(self.rdev.1.to_string()))?;
+        println!("mknod: {}", self.name);
+        Ok(())
     }
 }
 
-pub fn disconnect_nbd_device(nbd_device: &str) {
-    let output = Command::new("sudo")
-                .args(&["qemu-nbd", "-d", nbd_device])
-                .output()
-                .expect("Failed to execute command: qemu-nbd");
-
-    if !output.status.success() {
-        let error_message = String::from_utf8_lossy(&output.stderr);
-        eprintln!("Failed to disconnect {}: {}", nbd_device, error_message);
-        std::process::exit(1);
-    } else {
-        println!("disconnect: {}", nbd_device);
-    }
-}
-
-/// clean functions
-pub fn umount_temp_images() {
+/// clean functions, for whatever a run killed without cleanup left behind
+pub fn umount_temp_images() -> Result<()> {
+    // grep exits with 1 when nothing matches, so no `run` here
     let output = Command::new("bash")
         .arg("-c")
-        .arg("mount | grep temp_image | grep -o '[^ ]*temp_image'")
+        .arg("mount | grep temp_image | grep -o '[^ ]*temp_image[^ ]*'")
         .output()
-        .expect("Failed to execute command: mount");
+        .map_err(|err| Error::io("Failed to list mounts", err))?;
 
     if !output.status.success() {
         let error_message = String::from_utf8_lossy(&output.stderr);
@@ -231,17 +175,19 @@ pub fn umount_temp_images() {
         .map(|s| s.trim().to_string())
         .collect();
     for temp_image in mounted_temp_images {
-        umount(&temp_image);
+        if let Err(err) = Resource::Mount(temp_image).release() {
+            eprintln!("{}", err);
+        }
     };
-
+    Ok(())
 }
 
-pub fn disconnect_nbd_divices() {
+pub fn disconnect_nbd_divices() -> Result<()> {
     let output = Command::new("sh")
         .arg("-c")
         .arg("ps ax | grep -o '/dev/nbd[0-9]\\+'")
         .output()
-        .expect("Failed to execute command: ps ax");
+        .map_err(|err| Error::io("Failed to list nbd devices", err))?;
 
     if !output.status.success() {
         println!("There is no active nbd devices {}", String::from_utf8_lossy(&output.stderr));
@@ -254,6 +200,9 @@ pub fn disconnect_nbd_divices() {
         .map(|s| s.trim().to_string())
         .collect();
     for nbd_device in active_nbd_devices {
-        disconnect_nbd_device(&nbd_device)
+        if let Err(err) = Resource::Nbd(nbd_device).release() {
+            eprintln!("{}", err);
+        }
     };
+    Ok(())
 }
\ No newline at end of file
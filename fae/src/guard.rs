@@ -0,0 +1,170 @@
+//! Kernel state we set up on the host (nbd connections, loop devices,
+//! mounts, tap interfaces) and throwaway files are held by guards that tear
+//! them down when dropped. Live guards are also registered so Ctrl-C can
+//! release them before exiting.
+
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::error::{run, Result};
+
+/// Something on the host that has to be undone
+#[derive(Clone, Debug)]
+pub enum Resource {
+    /// nbd device connected with `qemu-nbd -c`
+    Nbd(String),
+    /// loop device attached with `losetup`
+    Loop(String),
+    /// mount point of a mounted image
+    Mount(String),
+    /// tap interface created with `ip tuntap add`
+    Tap(String),
+    /// file only needed while QEMU runs, such as an overlay
+    File(String),
+    /// bridge created with `ip link add type bridge`
+    Bridge(String),
+    /// physical interface put into a bridge, with the IPv4 addresses and
+    /// default gateway it had before
+    Uplink { device: String, addresses: Vec<String>, gateway: Option<String> },
+}
+
+impl Resource {
+    fn commands(&self) -> Vec<Vec<&str>> {
+        match self {
+            Resource::Nbd(device) => vec![vec!["qemu-nbd", "-d", device]],
+            Resource::Loop(device) => vec![vec!["losetup", "-d", device]],
+            Resource::Mount(mount_point) => vec![vec!["umount", mount_point]],
+            Resource::Tap(tap) => vec![vec!["ip", "link", "delete", tap]],
+            Resource::File(path) => vec![vec!["rm", "-f", path]],
+            Resource::Bridge(bridge) => vec![vec!["ip", "link", "delete", bridge, "type", "bridge"]],
+            Resource::Uplink { device, addresses, gateway } => {
+                let mut commands = vec![vec!["ip", "link", "set", device, "nomaster"]];
+                for address in addresses {
+                    commands.push(vec!["ip", "addr", "add", address, "dev", device]);
+                }
+                if let Some(gateway) = gateway {
+                    commands.push(vec!["ip", "route", "replace", "default", "via", gateway, "dev", device]);
+                }
+                commands
+            }
+        }
+    }
+
+    /// Undo every step, going on after a failed one and reporting the first
+    /// failure
+    pub fn release(&self) -> Result<()> {
+        let mut result = Ok(());
+        for command in self.commands() {
+            if let Err(err) = run(Command::new("sudo").args(command)) {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result?;
+        println!("Released {}", self);
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Resource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Resource::Nbd(device) => write!(f, "nbd device {}", device),
+            Resource::Loop(device) => write!(f, "loop device {}", device),
+            Resource::Mount(mount_point) => write!(f, "mount {}", mount_point),
+            Resource::Tap(tap) => write!(f, "tap {}", tap),
+            Resource::File(path) => write!(f, "file {}", path),
+            Resource::Bridge(bridge) => write!(f, "bridge {}", bridge),
+            Resource::Uplink { device, .. } => write!(f, "uplink {}", device),
+        }
+    }
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+/// Resources held by live guards, oldest first
+static ACTIVE: Mutex<Vec<(usize, Resource)>> = Mutex::new(Vec::new());
+
+/// Releases its resource when dropped
+#[derive(Debug)]
+pub struct Guard {
+    id: usize,
+    resource: Resource,
+}
+
+impl Guard {
+    pub fn new(resource: Resource) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        ACTIVE.lock().unwrap_or_else(|err| err.into_inner()).push((id, resource.clone()));
+        Guard { id, resource }
+    }
+
+    /// Release now and report failures, instead of only logging them on drop
+    pub fn release(self) -> Result<()> {
+        // unregistered first, dropping `self` afterwards is then a no-op
+        let mut active = ACTIVE.lock().unwrap_or_else(|err| err.into_inner());
+        let held = active.iter().any(|(id, _)| *id == self.id);
+        active.retain(|(id, _)| *id != self.id);
+        drop(active);
+        if held { self.resource.release() } else { Ok(()) }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap_or_else(|err| err.into_inner());
+        // already released, by `release` or the interrupt handler
+        if !active.iter().any(|(id, _)| *id == self.id) {
+            return;
+        }
+        active.retain(|(id, _)| *id != self.id);
+        drop(active);
+        if let Err(err) = self.resource.release() {
+            eprintln!("{}", err);
+        }
+    }
+}
+
+/// Release everything still held, newest first so mounts go before the
+/// devices under them
+pub fn release_all() {
+    let active = std::mem::take(&mut *ACTIVE.lock().unwrap_or_else(|err| err.into_inner()));
+    for (_, resource) in active.iter().rev() {
+        if let Err(err) = resource.release() {
+            eprintln!("{}", err);
+        }
+    }
+}
+
+/// On Ctrl-C release every guarded resource, then exit
+pub fn install_interrupt_handler() {
+    let result = ctrlc::set_handler(|| {
+        eprintln!("Interrupted, cleaning up");
+        release_all();
+        std::process::exit(130);
+    });
+    if let Err(err) = result {
+        eprintln!("Failed to install Ctrl-C handler: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_survives_a_panicking_holder() {
+        let _ = std::thread::spawn(|| {
+            let _active = ACTIVE.lock().unwrap();
+            panic!("a target thread failed while holding the registry");
+        }).join();
+        assert!(ACTIVE.is_poisoned());
+
+        let guard = Guard::new(Resource::File("/nonexistent/fae-test".to_string()));
+        let mut active = ACTIVE.lock().unwrap_or_else(|err| err.into_inner());
+        assert!(active.iter().any(|(id, _)| *id == guard.id));
+        // unregistered by hand, so dropping the guard runs nothing
+        active.retain(|(id, _)| *id != guard.id);
+    }
+}
@@ -0,0 +1,27 @@
+//! Firmware extraction, image generation and emulation, as used by the
+//! `cargo-fae` command line tool.
+//!
+//! The stages are configured with [`Extract`], [`Generate`] and [`Emulate`]
+//! and run on their own or together from a task file with [`Tasks`].
+
+mod assets;
+mod config;
+mod detector;
+mod emulator;
+mod error;
+mod extractor;
+mod generator;
+mod guard;
+mod tasks;
+mod utils;
+
+pub use assets::{Assets, ASSETS_ENV};
+pub use config::{project_config_path, user_config_path, Config, ConfigLayer, DEFAULT_OUTPUT_DIR, PROJECT_CONFIG};
+pub use detector::detect_arch;
+pub use emulator::{qmp_socket, BlockDevice, BootMonitor, BootVerdict, Console, ConsoleEvent, Instance, MachineProfile, NetworkMode, NetworkPlan,
+                   Observed, Overlay, ProfileOverrides, Qmp, RunOptions, VmStatus};
+pub use error::{Error, Result};
+pub use extractor::{Component, ComponentKind, Extraction};
+pub use guard::install_interrupt_handler;
+pub use tasks::{Emulate, Extract, Generate, StageStatus, Target, TargetOutcome, Tasks};
+pub use utils::{disconnect_nbd_divices, parse_size, umount_temp_images, Arch, GuestNetwork, GuestNetworkType, ImageInfo, ImageType};
@@ -1,13 +1,6 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use serde::{Deserialize, Serialize};
-mod extractor;
-mod generator;
-mod emulator;
-mod utils;
-use extractor::extract_firmware;
-use generator::generate_image;
-use emulator::run_emulation;
-use utils::*;
+use fae::*;
 
 #[derive(Parser)]
 #[command(name = "firmware_tool")]
@@ -15,10 +8,75 @@ use utils::*;
 #[command(author = "你的名字")]
 #[command(about = "用于提取和模拟固件的工具")]
 struct Cli {
+    /// directory holding binaries/ and profiles/ (default: $FAE_ASSETS, the config file or the install location)
+    #[arg(long, global = true)]
+    assets: Option<String>,
+    /// tap interface for the guest network (default: tap-qemu)
+    #[arg(long, global = true)]
+    tap: Option<String>,
+    /// host address on the tap, with prefix length (default: 192.168.1.1/24)
+    #[arg(long, global = true)]
+    host_ip: Option<String>,
+    /// bridge of the host-bridge network (default: br-fae)
+    #[arg(long, global = true)]
+    bridge: Option<String>,
+    /// physical interface added to the host bridge, its addresses move to the bridge while QEMU runs
+    #[arg(long, global = true)]
+    uplink: Option<String>,
+    /// guest memory when the machine profile sets none (default: 256M)
+    #[arg(long, global = true)]
+    memory: Option<String>,
     #[command(subcommand)]
     command: Command,
 }
 
+/// Settings of the guest network script written into the image, shared by
+/// the subcommands that generate
+#[derive(Debug, Args, Deserialize, Serialize)]
+struct GuestNetworkOptions {
+    /// what the guest network script does in the guest-bridge network mode, all but default run the firmware's rc scripts (default: default, bridge eth0 into a new br0)
+    #[arg(long, value_enum)]
+    net_type: Option<GuestNetworkType>,
+    /// bridge inside the guest, the firmware's own for all types but default (default: br0)
+    #[arg(long)]
+    net_bridge: Option<String>,
+    /// LAN interface brought up with the guest bridge (default: eth0)
+    #[arg(long)]
+    net_interface: Option<String>,
+}
+
+/// How to emulate, shared by the subcommands that do
+#[derive(Debug, Args, Deserialize, Serialize)]
+struct EmulateOptions {
+    /// machine profile name in the assets' profiles/ or a .toml path (default: the one of the arch)
+    #[arg(long)]
+    profile: Option<String>,
+    /// wait for gdb on port 1234 before the guest starts
+    #[arg(long)]
+    debug: bool,
+    /// run QEMU detached and log the serial console with timestamps
+    #[arg(long)]
+    headless: bool,
+    /// serial log file, implies --headless (default: <image>.<start time>.serial.log)
+    #[arg(long)]
+    serial_log: Option<String>,
+    /// also show the console of a headless run on the terminal
+    #[arg(long)]
+    tee: bool,
+    /// stop QEMU after this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+    /// watch the console for this many seconds and report whether the firmware booted
+    #[arg(long)]
+    boot_timeout: Option<u64>,
+    /// run on a qcow2 overlay backed by the image, discarded or kept afterwards (default: off, writes go into the image)
+    #[arg(long, value_enum)]
+    overlay: Option<Overlay>,
+    /// how the guest network reaches the host (default: tap)
+    #[arg(long, value_enum)]
+    network: Option<NetworkMode>,
+}
+
 #[derive(Debug, Subcommand, Deserialize, Serialize,)]
 enum Command {
     /// extract root filesystem from .bin firmware
@@ -27,9 +85,9 @@ enum Command {
         #[arg(short, long)]
         firmware: String,
 
-        /// Extract files/folders to a custom directory (default: current working directory)
-        #[arg(short, long, default_value_t = String::from("../outputs"))]
-        directory: String,
+        /// Extract files/folders to a custom directory (default: output_dir of the config, ../outputs)
+        #[arg(short, long)]
+        directory: Option<String>,
     },
     /// generate image according to root filesystem
     Generate {
@@ -39,16 +97,55 @@ enum Command {
         image: String,
         /// image type qcow2 or raw
         type_image: ImageType,
-        /// arch: arm, mips, mipsel
-        arch: Arch,
+        /// arch: arm, mips, mipsel (default: detected from the rootfs binaries)
+        #[arg(short, long)]
+        arch: Option<Arch>,
+        /// build the image in user space, without sudo, nbd or mount
+        #[arg(long)]
+        rootless: bool,
+        /// image size like 512M or 2G (default: computed from the rootfs)
+        #[arg(long, value_parser = parse_size)]
+        size: Option<u64>,
+        #[command(flatten)]
+        guest_network: GuestNetworkOptions,
     },
     /// run emulation for the firmware
     Emulate {
         /// image regarded as root filesystem, qcow2 or raw image
         image: String,
-        arch: Arch,
-        #[arg(default_value_t = false)]
-        debug: bool,
+        /// arch: arm, mips, mipsel (default: the arch recorded when the image was generated)
+        #[arg(short, long)]
+        arch: Option<Arch>,
+        /// start from this VM snapshot of the qcow2 image instead of booting
+        #[arg(long)]
+        snapshot: Option<String>,
+        #[command(flatten)]
+        options: EmulateOptions,
+    },
+    /// boot a qcow2 image until the guest is ready, save it as a VM snapshot and stop
+    Snapshot {
+        /// qcow2 image to boot and save the snapshot into
+        image: String,
+        /// snapshot name, for `emulate --snapshot`
+        name: String,
+        /// arch: arm, mips, mipsel (default: the arch recorded when the image was generated)
+        #[arg(short, long)]
+        arch: Option<Arch>,
+        #[command(flatten)]
+        options: EmulateOptions,
+    },
+    /// boot an image running the firmware's rc scripts and learn the network they set up, for `--network learned`
+    Learn {
+        /// image to learn the network of
+        image: String,
+        /// arch: arm, mips, mipsel (default: the arch recorded when the image was generated)
+        #[arg(short, long)]
+        arch: Option<Arch>,
+        /// seconds the firmware gets to set up its network
+        #[arg(long, default_value_t = 60)]
+        wait: u64,
+        #[command(flatten)]
+        options: EmulateOptions,
     },
     /// generate and emulate
     GenerateAndEmulate {
@@ -58,10 +155,19 @@ enum Command {
         image: String,
         /// image type qcow2 or raw
         type_image: ImageType,
-        /// architecture: arm mips mipsel
-        arch: Arch,
-        #[arg(default_value_t = false)]
-        debug: bool,
+        /// architecture: arm mips mipsel (default: detected from the rootfs binaries)
+        #[arg(short, long)]
+        arch: Option<Arch>,
+        /// build the image in user space, without sudo, nbd or mount
+        #[arg(long)]
+        rootless: bool,
+        /// image size like 512M or 2G (default: computed from the rootfs)
+        #[arg(long, value_parser = parse_size)]
+        size: Option<u64>,
+        #[command(flatten)]
+        guest_network: GuestNetworkOptions,
+        #[command(flatten)]
+        options: EmulateOptions,
     },
     /// test
     Test {
@@ -73,75 +179,231 @@ enum Command {
     Umount,
     /// run tasks according to task file
     RunTasks {
-        /// 
+        /// task file in toml format
         task_file: String,
+        /// targets run at the same time, each on its own tap and subnet (default: jobs of the config, 1)
+        #[arg(short, long)]
+        jobs: Option<usize>,
     },
+    /// show where the kernels, busybox and agents are taken from and which are missing
+    Assets,
+    /// control a running emulation through its QMP socket
+    Qmp {
+        /// QMP socket (default: the one of the emulation on --tap)
+        #[arg(long)]
+        socket: Option<String>,
+        #[command(subcommand)]
+        action: QmpAction,
+    },
+
+}
 
+#[derive(Debug, Subcommand, Deserialize, Serialize)]
+enum QmpAction {
+    /// whether the guest is running or paused
+    Status,
+    Pause,
+    Resume,
+    /// save the machine state into the qcow2 image
+    Savevm { name: String },
+    /// go back to a saved machine state
+    Loadvm { name: String },
+    /// delete a saved machine state
+    Delvm { name: String },
+    /// list the saved machine states
+    Snapshots,
+    /// list the block devices and their images
+    Block,
+    /// show the NICs and their backends
+    Network,
+    /// ask the guest to power off
+    Powerdown,
+    /// stop QEMU
+    Quit,
 }
 
 fn main() {
     let cli = Cli::parse();
+    install_interrupt_handler();
+
+    if let Err(err) = start(&cli) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn generate_task(rootfs: &str, image: &str, type_image: &ImageType, arch: &Option<Arch>, rootless: bool, size: Option<u64>,
+                 guest_network: &GuestNetworkOptions) -> Generate {
+    let defaults = GuestNetwork::default();
+    let guest_network = GuestNetwork {
+        network_type: guest_network.net_type.unwrap_or(defaults.network_type),
+        bridge: guest_network.net_bridge.clone().unwrap_or(defaults.bridge),
+        interface: guest_network.net_interface.clone().unwrap_or(defaults.interface),
+    };
+    let mut generate = Generate::new(image, type_image.clone()).rootfs(rootfs).rootless(rootless).guest_network(guest_network);
+    generate.arch = arch.clone();
+    if let Some(size) = size {
+        generate = generate.size(size);
+    }
+    generate
+}
+
+fn emulate_task(image: &str, arch: &Option<Arch>, options: &EmulateOptions) -> Emulate {
+    Emulate {
+        arch: arch.clone(),
+        profile: options.profile.clone(),
+        timeout: options.timeout,
+        headless: options.headless,
+        serial_log: options.serial_log.clone(),
+        tee: options.tee,
+        boot_timeout: options.boot_timeout,
+        overlay: options.overlay.unwrap_or_default(),
+        network: options.network.unwrap_or_default(),
+        ..Emulate::new(image).debug(options.debug)
+    }
+}
 
-    match &cli.command {
+/// Settings given on the command line, the top config layer
+fn config_layer(cli: &Cli) -> ConfigLayer {
+    ConfigLayer {
+        assets: cli.assets.clone(),
+        tap: cli.tap.clone(),
+        host_ip: cli.host_ip.clone(),
+        bridge: cli.bridge.clone(),
+        uplink: cli.uplink.clone(),
+        memory: cli.memory.clone(),
+        jobs: match cli.command {
+            Command::RunTasks { jobs, .. } => jobs,
+            _ => None,
+        },
+        ..ConfigLayer::default()
+    }
+}
+
+/// Set up the config, with the `[config]` of a task file if one is run,
+/// then check the assets and run the command
+fn start(cli: &Cli) -> Result<()> {
+    let tasks = match &cli.command {
+        Command::RunTasks { task_file, .. } => Some(Tasks::load(task_file)?),
+        _ => None,
+    };
+    Config::init(Config::load(tasks.as_ref().and_then(|tasks| tasks.config.as_ref()), &config_layer(cli))?);
+    check_assets(cli)?;
+    run(&cli.command, tasks)
+}
+
+/// Resolve the assets up front for the commands that use them, so a wrong
+/// location is reported before any work is done
+fn check_assets(cli: &Cli) -> Result<()> {
+    match cli.command {
+        Command::Extract { .. } | Command::Test { .. } | Command::Clean {} | Command::Umount | Command::Qmp { .. } => Ok(()),
+        _ => Assets::init(cli.assets.as_deref()).map(|_| ()),
+    }
+}
+
+/// Print the verdict of a watched boot, failing unless the guest booted
+fn report_boot(verdict: Option<BootVerdict>) -> Result<()> {
+    match verdict {
+        Some(verdict) if !verdict.is_booted() => Err(Error::Invalid(format!("Boot failed, {}", verdict))),
+        Some(verdict) => {
+            println!("Boot verdict: {}", verdict);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// `tasks` is the task file of `run-tasks` when it was already loaded
+fn run(command: &Command, tasks: Option<Tasks>) -> Result<()> {
+    match command {
         Command::Extract { firmware, directory } => {
-            extract_firmware(firmware, directory);
+            let extraction = Extract { directory: directory.clone(), ..Extract::new(firmware) }.run()?;
+            println!("Extracted {} components into {}",
+                extraction.components.len(), extraction.directory.display());
+        }
+        Command::Generate { rootfs, image, type_image, arch, rootless, size, guest_network } => {
+            generate_task(rootfs, image, type_image, arch, *rootless, *size, guest_network).run()?;
+        }
+        Command::Emulate { image, arch, snapshot, options } => {
+            let emulate = Emulate { snapshot: snapshot.clone(), ..emulate_task(image, arch, options) };
+            report_boot(emulate.run()?)?;
         }
-        Command::Generate { rootfs, image, type_image, arch} => {
-            generate_image(rootfs, image, type_image, arch);
+        Command::Snapshot { image, name, arch, options } => {
+            report_boot(emulate_task(image, arch, options).save_snapshot(name).run()?)?;
         }
-        Command::Emulate { image, arch, debug} => {
-            run_emulation(image, arch, debug);
+        Command::Learn { image, arch, wait, options } => {
+            let plan = emulate_task(image, arch, options).learn(*wait)?;
+            println!("Learned network of {}: {}", image, plan);
         }
-        Command::GenerateAndEmulate {rootfs, image, type_image, arch, debug} => {
-            generate_image(rootfs, image, type_image, arch);
-            run_emulation(image, arch, debug);
+        Command::GenerateAndEmulate {rootfs, image, type_image, arch, rootless, size, guest_network, options} => {
+            generate_task(rootfs, image, type_image, arch, *rootless, *size, guest_network).run()?;
+            report_boot(emulate_task(image, arch, options).run()?)?;
         }
-        Command::RunTasks { task_file } => {
+        Command::RunTasks { task_file, .. } => {
             println!("Run task: {}", task_file);
-            run_tasks(task_file);
-
+            tasks.map_or_else(|| Tasks::load(task_file), Ok)?.run()?;
         }
         Command::Test { input } => {
-            println!("{}", test_func(&input));
+            println!("{}", test_func(input)?);
         }
         Command::Clean {  } => {
-            utils::umount_temp_images();
-            utils::disconnect_nbd_divices();
+            umount_temp_images()?;
+            disconnect_nbd_divices()?;
+        }
+        Command::Umount => {
+            umount_temp_images()?;
+        }
+        Command::Assets => {
+            print!("{}", Assets::get()?.report());
+        }
+        Command::Qmp { socket, action } => {
+            qmp_command(socket.as_deref(), action)?;
+        }
+    }
+    Ok(())
+}
+
+fn qmp_command(socket: Option<&str>, action: &QmpAction) -> Result<()> {
+    let socket = match socket {
+        Some(socket) => std::path::PathBuf::from(socket),
+        None => qmp_socket(&Config::get()?.tap),
+    };
+    let mut qmp = Qmp::connect(&socket, std::time::Duration::from_secs(1))?;
+    match action {
+        QmpAction::Status => {
+            let status = qmp.status()?;
+            println!("{}", status.status);
         }
-        Command::Umount {  } => {
-            utils::umount_temp_images();
+        QmpAction::Pause => qmp.pause()?,
+        QmpAction::Resume => qmp.resume()?,
+        QmpAction::Savevm { name } => qmp.save_snapshot(name)?,
+        QmpAction::Loadvm { name } => qmp.load_snapshot(name)?,
+        QmpAction::Delvm { name } => qmp.delete_snapshot(name)?,
+        QmpAction::Snapshots => print!("{}", qmp.snapshots()?),
+        QmpAction::Block => {
+            for device in qmp.block_devices()? {
+                println!("{:<12}{:<8}{}{}", device.device, device.format.as_deref().unwrap_or("-"),
+                    device.file.as_deref().unwrap_or("(empty)"), if device.read_only { " (read-only)" } else { "" });
+            }
         }
+        QmpAction::Network => print!("{}", qmp.network()?),
+        QmpAction::Powerdown => qmp.powerdown()?,
+        QmpAction::Quit => qmp.quit()?,
     }
+    Ok(())
 }
 
 
 // use std::path::Path;
-fn test_func(_path: &str) -> String {
+fn test_func(_path: &str) -> Result<String> {
     let tasks: Tasks = Tasks {
-        extract: Some(Extract { firmware: "czx".to_string() , directory: "czx".to_string() }),
-        generate: None,
-        emulate: Some(Emulate { image: "czx".to_string(), arch: Arch::Arm, debug: true }),    
+        config: None,
+        targets: vec![Target {
+            extract: Some(Extract::new("czx").directory("czx")),
+            emulate: Some(Emulate::new("czx").arch(Arch::Arm).debug(true)),
+            ..Target::default()
+        }],
     };
 
-    let toml_string = toml::to_string(&tasks).expect("Failed to serialize config");
-    toml_string
+    toml::to_string(&tasks).map_err(|err| Error::parse("tasks", err))
 }
-
-fn run_tasks(task_file: &str) {
-    let config_content  = std::fs::read_to_string(task_file).expect("Failed to read task file");
-    let tasks: Tasks = toml::de::from_str(&config_content).expect("Unable to parse TOML");
-    
-    if let Some(Extract {firmware, directory}) = &tasks.extract {
-        println!("Extracting firmware {} to directory {}", firmware, directory);
-        extract_firmware(firmware, directory);
-    }
-    if let Some(Generate {rootfs, image,type_image, arch}) = &tasks.generate {
-        println!("Generating firmware {} for architecture {:?}", image, arch);
-        generate_image(rootfs, image, type_image, arch);
-    }
-    if let Some(Emulate {image, arch, debug}) = &tasks.emulate {
-        println!("Emulating firmware {} on architecture {:?}", image, arch);
-        run_emulation(image, arch, debug);
-    }
-
-}
\ No newline at end of file
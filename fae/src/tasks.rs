@@ -0,0 +1,788 @@
+//! The stages of a run, as read from a task file or built in code:
+//!
+//! ```no_run
+//! use fae::{Emulate, Generate, ImageType};
+//!
+//! Generate::new("image.qcow2", ImageType::Qcow2)
+//!     .rootfs("squashfs-root")
+//!     .rootless(true)
+//!     .run()?;
+//! Emulate::new("image.qcow2").run()?;
+//! # Ok::<(), fae::Error>(())
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, ConfigLayer};
+use crate::emulator::{default_log, learn_log, run_emulation, run_stamp, BootVerdict, Console, Instance, NetworkMode, NetworkPlan,
+                      Observed, Overlay, ProfileOverrides, RunOptions};
+use crate::error::{Error, Result};
+use crate::extractor::{extract_firmware, Extraction};
+use crate::generator::generate_image;
+use crate::utils::{parse_size, Arch, GuestNetwork, ImageInfo, ImageType};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Extract {
+    pub firmware: String,
+    /// the config's `output_dir` when omitted
+    pub directory: Option<String>,
+}
+
+impl Extract {
+    pub fn new(firmware: &str) -> Self {
+        Extract { firmware: firmware.to_string(), directory: None }
+    }
+
+    pub fn directory(mut self, directory: &str) -> Self {
+        self.directory = Some(directory.to_string());
+        self
+    }
+
+    pub fn run(&self) -> Result<Extraction> {
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => &Config::get()?.output_dir,
+        };
+        println!("Extracting firmware {} to directory {}", self.firmware, directory);
+        extract_firmware(&self.firmware, directory)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Generate {
+    /// defaults to the root filesystem found by `[extract]`
+    pub rootfs: Option<String>,
+    /// defaults to `image.<type>` in the directory `[extract]` wrote to
+    pub image: Option<String>,
+    #[serde(default)]
+    pub type_image: ImageType,
+    /// detected from the rootfs binaries when omitted
+    pub arch: Option<Arch>,
+    /// build the image in user space, without sudo, nbd or mount
+    #[serde(default)]
+    pub rootless: bool,
+    /// image size like `512M` or `2G`, computed from the rootfs when omitted
+    pub size: Option<String>,
+    /// settings of the guest network script, `[generate.guest_network]`,
+    /// used by the `GuestBridge` network mode
+    #[serde(default)]
+    pub guest_network: GuestNetwork,
+}
+
+impl Generate {
+    pub fn new(image: &str, type_image: ImageType) -> Self {
+        Generate { image: Some(image.to_string()), type_image, ..Generate::default() }
+    }
+
+    pub fn rootfs(mut self, rootfs: &str) -> Self {
+        self.rootfs = Some(rootfs.to_string());
+        self
+    }
+
+    pub fn arch(mut self, arch: Arch) -> Self {
+        self.arch = Some(arch);
+        self
+    }
+
+    pub fn rootless(mut self, rootless: bool) -> Self {
+        self.rootless = rootless;
+        self
+    }
+
+    /// Image size in bytes
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size.to_string());
+        self
+    }
+
+    pub fn guest_network(mut self, guest_network: GuestNetwork) -> Self {
+        self.guest_network = guest_network;
+        self
+    }
+
+    /// Build the image, returns the architecture it was built for
+    pub fn run(&self) -> Result<Arch> {
+        let image = self.image.as_deref()
+            .ok_or_else(|| Error::Invalid("No image path to generate".to_string()))?;
+        let rootfs = self.rootfs.as_deref()
+            .ok_or_else(|| Error::Invalid(format!("No rootfs to generate {} from", image)))?;
+        let size = self.size.as_deref().map(parse_size).transpose()?;
+        generate_image(rootfs, image, &self.type_image, &self.arch, self.rootless, size, &self.guest_network)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Emulate {
+    /// image regarded as root filesystem, qcow2 or raw image, defaults to
+    /// the one `[generate]` built
+    pub image: Option<String>,
+    /// taken from `[generate]` or the image info it wrote when omitted
+    pub arch: Option<Arch>,
+    #[serde(default)]
+    pub debug: bool,
+    /// machine profile name or `.toml` path, defaults to the one of `arch`
+    pub profile: Option<String>,
+    /// fields replaced in the selected profile, `[emulate.overrides]`
+    pub overrides: Option<ProfileOverrides>,
+    /// seconds after which QEMU is stopped, runs until the guest powers off
+    /// when omitted
+    pub timeout: Option<u64>,
+    /// run QEMU detached and log the serial console instead of showing it
+    #[serde(default)]
+    pub headless: bool,
+    /// serial log of a headless run, `<image>.<start time>.serial.log` when
+    /// omitted; setting it makes the run headless
+    pub serial_log: Option<String>,
+    /// show the console of a headless run on the terminal as well
+    #[serde(default)]
+    pub tee: bool,
+    /// seconds to wait for signs of a boot; the console is watched and a
+    /// headless QEMU stopped at the verdict
+    pub boot_timeout: Option<u64>,
+    /// start from this VM snapshot of the qcow2 image instead of booting
+    pub snapshot: Option<String>,
+    /// boot until the guest is ready, save it as a VM snapshot of this name
+    /// and stop, for later runs to start from with `snapshot`
+    pub save_snapshot: Option<String>,
+    /// `Discard` or `Keep` to run on a qcow2 overlay backed by the image,
+    /// which is then left as it is and can be shared by concurrent runs
+    #[serde(default)]
+    pub overlay: Overlay,
+    /// `Tap`, `HostBridge` on the bridge and uplink of the config,
+    /// `GuestBridge` with the guest network script set up by `[generate]`,
+    /// or `Learned` with what `cargo-fae learn` found
+    #[serde(default)]
+    pub network: NetworkMode,
+}
+
+impl Emulate {
+    pub fn new(image: &str) -> Self {
+        Emulate { image: Some(image.to_string()), ..Emulate::default() }
+    }
+
+    pub fn arch(mut self, arch: Arch) -> Self {
+        self.arch = Some(arch);
+        self
+    }
+
+    /// Wait for gdb on port 1234 before the guest starts
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    pub fn profile(mut self, profile: &str) -> Self {
+        self.profile = Some(profile.to_string());
+        self
+    }
+
+    pub fn overrides(mut self, overrides: ProfileOverrides) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+
+    pub fn timeout(mut self, seconds: u64) -> Self {
+        self.timeout = Some(seconds);
+        self
+    }
+
+    /// Watch the boot, see [`BootVerdict`]
+    pub fn boot_timeout(mut self, seconds: u64) -> Self {
+        self.boot_timeout = Some(seconds);
+        self
+    }
+
+    /// Start from the VM snapshot `name` instead of booting
+    pub fn snapshot(mut self, name: &str) -> Self {
+        self.snapshot = Some(name.to_string());
+        self
+    }
+
+    /// Boot, save the ready guest as the VM snapshot `name` and stop
+    pub fn save_snapshot(mut self, name: &str) -> Self {
+        self.save_snapshot = Some(name.to_string());
+        self
+    }
+
+    pub fn network(mut self, network: NetworkMode) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn overlay(mut self, overlay: Overlay) -> Self {
+        self.overlay = overlay;
+        self
+    }
+
+    /// Log the serial console to `serial_log`, or the default log when None
+    pub fn headless(mut self, serial_log: Option<&str>, tee: bool) -> Self {
+        self.headless = true;
+        self.serial_log = serial_log.map(str::to_string);
+        self.tee = tee;
+        self
+    }
+
+    /// Emulate on the tap and subnet of the config. With a boot timeout the
+    /// verdict on the boot is returned.
+    pub fn run(&self) -> Result<Option<BootVerdict>> {
+        self.run_on(&Instance::slot(Config::get()?, 0)?)
+    }
+
+    pub fn run_on(&self, instance: &Instance) -> Result<Option<BootVerdict>> {
+        let image = self.image.as_deref()
+            .ok_or_else(|| Error::Invalid("No image to emulate".to_string()))?;
+        let overrides = self.overrides.clone().unwrap_or_default();
+        let options = self.options();
+        let mut instance = instance.clone();
+        instance.console = match instance.console {
+            Console::Interactive if !self.headless && self.serial_log.is_none() => Console::Interactive,
+            Console::Interactive => Console::Headless {
+                log: self.serial_log.as_ref().map_or_else(|| default_log(image), PathBuf::from),
+                tee: self.tee,
+            },
+            Console::Headless { log, tee } => Console::Headless {
+                log: self.serial_log.as_ref().map_or(log, PathBuf::from),
+                tee: tee || self.tee,
+            },
+        };
+        run_emulation(image, &self.arch, self.profile.as_deref(), &overrides, &instance, &options)
+    }
+
+    /// Boot once running the firmware's rc scripts, give them `wait` seconds
+    /// to set up the network and learn it from the serial console. The plan
+    /// is kept in the image info for runs in the `Learned` network mode.
+    pub fn learn(&self, wait: u64) -> Result<NetworkPlan> {
+        let image = self.image.as_deref()
+            .ok_or_else(|| Error::Invalid("No image to learn the network of".to_string()))?;
+        let overrides = self.overrides.clone().unwrap_or_default();
+        let log = self.serial_log.as_ref().map_or_else(|| learn_log(image), PathBuf::from);
+        let instance = Instance {
+            console: Console::Headless { log: log.clone(), tee: self.tee },
+            ..Instance::slot(Config::get()?, 0)?
+        };
+        // the firmware sets up the guest side itself, the host only needs the tap
+        let options = RunOptions {
+            loadvm: None,
+            savevm: None,
+            network: NetworkMode::Tap,
+            learn: Some(Duration::from_secs(wait)),
+            ..self.options()
+        };
+        match run_emulation(image, &self.arch, self.profile.as_deref(), &overrides, &instance, &options)? {
+            Some(verdict) if verdict.is_booted() => {}
+            Some(verdict) => return Err(Error::Invalid(format!("Learning boot of {} failed, {}", image, verdict))),
+            None => return Err(Error::Invalid(format!("Learning boot of {} stopped before the network was reported", image))),
+        }
+        let plan = NetworkPlan::derive(Observed::from_log(&log)?)?;
+        let mut info = ImageInfo::load(image).unwrap_or_default();
+        info.arch = info.arch.or(self.arch.clone());
+        info.learned_network = Some(plan.clone());
+        info.save(image)?;
+        Ok(plan)
+    }
+
+    fn options(&self) -> RunOptions {
+        RunOptions {
+            timeout: self.timeout.map(Duration::from_secs),
+            boot_timeout: self.boot_timeout.map(Duration::from_secs),
+            debug: self.debug,
+            loadvm: self.snapshot.clone(),
+            savevm: self.save_snapshot.clone(),
+            overlay: self.overlay,
+            network: self.network,
+            learn: None,
+        }
+    }
+}
+
+/// One firmware taken through some or all of the stages. Each stage
+/// defaults to what the ones before it produced, so a target with only
+/// `firmware` is extracted, generated and emulated.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Target {
+    /// shown in the summary, the firmware or image file name when omitted
+    pub name: Option<String>,
+    /// firmware for `[extract]`, which runs even when not given
+    pub firmware: Option<String>,
+    pub extract: Option<Extract>,
+    pub generate: Option<Generate>,
+    pub emulate: Option<Emulate>,
+}
+
+/// What running a target came to
+#[derive(Debug)]
+pub struct TargetOutcome {
+    /// of extract, generate and emulate
+    pub status: [StageStatus; 3],
+    /// of the emulation, when its boot was watched
+    pub verdict: Option<BootVerdict>,
+    /// the first error
+    pub result: Result<()>,
+}
+
+/// How a stage of a target went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageStatus {
+    /// the target has no such stage
+    None,
+    Ok,
+    Failed,
+    /// not run because an earlier stage failed
+    Skipped,
+}
+
+impl StageStatus {
+    pub fn to_str(&self) -> &str {
+        match self {
+            StageStatus::None => "-",
+            StageStatus::Ok => "ok",
+            StageStatus::Failed => "failed",
+            StageStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Report which stage of a target failed. The stages after it are
+/// skipped, each one works on what the previous produced.
+fn stage_failed(stage: &str, err: Error) -> Error {
+    eprintln!("[{}] failed: {}", stage, err);
+    err
+}
+
+/// What the stages run so far produced, the defaults of the next ones
+#[derive(Debug, Default)]
+struct StageContext {
+    /// directory `[extract]` carved into
+    extraction_dir: Option<PathBuf>,
+    rootfs: Option<PathBuf>,
+    arch: Option<Arch>,
+    image: Option<String>,
+}
+
+fn file_name(path: &str) -> String {
+    std::path::Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+impl Target {
+    /// The name, or the first file the target works on
+    pub fn label(&self) -> String {
+        self.name.clone()
+            .or_else(|| self.firmware.as_deref().map(file_name))
+            .or_else(|| self.extract.as_ref().map(|extract| file_name(&extract.firmware)))
+            .or_else(|| self.generate.as_ref().and_then(|generate| generate.image.as_deref()).map(file_name))
+            .or_else(|| self.emulate.as_ref().and_then(|emulate| emulate.image.as_deref()).map(file_name))
+            .unwrap_or_else(|| "(empty)".to_string())
+    }
+
+    /// The stages to run. `firmware` adds `[extract]`, and makes the full
+    /// pipeline when it is all the target has.
+    fn stages(&self) -> (Option<Extract>, Option<Generate>, Option<Emulate>) {
+        let extract = self.extract.clone().or_else(|| self.firmware.as_deref().map(Extract::new));
+        let (generate, emulate) = match (&self.firmware, &self.extract, &self.generate, &self.emulate) {
+            (Some(_), None, None, None) => (
+                Some(Generate::default()),
+                Some(Emulate::default()),
+            ),
+            _ => (self.generate.clone(), self.emulate.clone()),
+        };
+        (extract, generate, emulate)
+    }
+
+    /// Run the stages in order: extract, generate, emulate. A boot that
+    /// was watched and did not succeed fails the emulate stage.
+    pub fn run(&self) -> TargetOutcome {
+        match Config::get().and_then(|config| Instance::slot(config, 0)) {
+            Ok(instance) => self.run_on(&instance),
+            Err(err) => TargetOutcome { status: [StageStatus::None; 3], verdict: None, result: Err(err) },
+        }
+    }
+
+    /// `run`, emulating on `instance`
+    pub fn run_on(&self, instance: &Instance) -> TargetOutcome {
+        let (extract, generate, emulate) = self.stages();
+        let mut status = [
+            if extract.is_some() { StageStatus::Skipped } else { StageStatus::None },
+            if generate.is_some() { StageStatus::Skipped } else { StageStatus::None },
+            if emulate.is_some() { StageStatus::Skipped } else { StageStatus::None },
+        ];
+        let mut verdict = None;
+        let result = Self::run_stages(extract, generate, emulate, instance, &mut status, &mut verdict);
+        TargetOutcome { status, verdict, result }
+    }
+
+    fn run_stages(extract: Option<Extract>, generate: Option<Generate>, emulate: Option<Emulate>,
+                  instance: &Instance, status: &mut [StageStatus; 3], verdict: &mut Option<BootVerdict>) -> Result<()> {
+        let mut context = StageContext::default();
+        if let Some(extract) = extract {
+            status[0] = StageStatus::Failed;
+            let extraction = extract.run().map_err(|err| stage_failed("extract", err))?;
+            status[0] = StageStatus::Ok;
+            context.extraction_dir = Some(extraction.directory);
+            context.rootfs = extraction.rootfs;
+        }
+        if let Some(mut generate) = generate {
+            if generate.rootfs.is_none() {
+                generate.rootfs = context.rootfs.as_ref().map(|rootfs| rootfs.to_string_lossy().into_owned());
+            }
+            if generate.image.is_none() {
+                generate.image = context.extraction_dir.as_ref().map(|directory| {
+                    directory.join(format!("image.{}", generate.type_image.to_str())).to_string_lossy().into_owned()
+                });
+            }
+            println!("Generating firmware {} for architecture {:?}", generate.image.as_deref().unwrap_or("?"), generate.arch);
+            status[1] = StageStatus::Failed;
+            let arch = generate.run().map_err(|err| stage_failed("generate", err))?;
+            status[1] = StageStatus::Ok;
+            context.arch = Some(arch);
+            context.image = generate.image;
+        }
+        if let Some(mut emulate) = emulate {
+            if emulate.image.is_none() {
+                emulate.image = context.image;
+            }
+            if emulate.arch.is_none() {
+                emulate.arch = context.arch;
+            }
+            println!("Emulating firmware {} on architecture {:?}", emulate.image.as_deref().unwrap_or("?"), emulate.arch);
+            status[2] = StageStatus::Failed;
+            *verdict = emulate.run_on(instance).map_err(|err| stage_failed("emulate", err))?;
+            if let Some(verdict) = verdict.as_ref().filter(|verdict| !verdict.is_booted()) {
+                return Err(stage_failed("emulate", Error::Invalid(format!("Boot failed, {}", verdict))));
+            }
+            status[2] = StageStatus::Ok;
+        }
+        Ok(())
+    }
+}
+
+/// A task file: `[config]`, stage settings under `[defaults]` that every
+/// target having that stage inherits, and the `[[targets]]`. Stages at the
+/// top level, as in single firmware task files, make one more target.
+///
+/// ```toml
+/// [defaults.generate]
+/// type_image = "Qcow2"
+/// rootless = true
+///
+/// [[targets]]
+/// name = "DIR825"
+/// extract = { firmware = "../firmwares/mips/DIR825B1_FW201EUB15.bin" }
+/// generate = { image = "../outputs/DIR825.qcow2" }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Tasks {
+    /// settings for this task file, over the user and project config
+    pub config: Option<ConfigLayer>,
+    #[serde(default)]
+    pub targets: Vec<Target>,
+}
+
+/// Fill in what `target` leaves out from `defaults`, table by table
+fn inherit(target: &mut toml::value::Table, defaults: &toml::value::Table) {
+    for (key, default) in defaults {
+        match (target.get_mut(key), default) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(default)) => inherit(table, default),
+            (Some(_), _) => {}
+            (None, _) => {
+                target.insert(key.clone(), default.clone());
+            }
+        }
+    }
+}
+
+impl Tasks {
+    pub fn load(task_file: &str) -> Result<Self> {
+        let config_content = std::fs::read_to_string(task_file)
+            .map_err(|err| Error::io(format!("Failed to read task file {}", task_file), err))?;
+        let mut tasks = Self::parse(&config_content).map_err(|err| Error::parse(task_file, err))?;
+        // paths of `[config]` are relative to the task file, like those of a config file
+        let directory = Path::new(task_file).parent().unwrap_or(Path::new(""));
+        tasks.config = tasks.config.map(|config| config.relative_to(directory));
+        Ok(tasks)
+    }
+
+    fn parse(content: &str) -> std::result::Result<Self, toml::de::Error> {
+        let mut file: toml::value::Table = toml::de::from_str(content)?;
+        let config = file.remove("config").map(|config| config.try_into()).transpose()?;
+        let defaults = match file.remove("defaults") {
+            Some(toml::Value::Table(defaults)) => defaults,
+            _ => toml::value::Table::new(),
+        };
+        let mut targets = match file.remove("targets") {
+            Some(targets) => targets.try_into::<Vec<toml::value::Table>>()?,
+            None => Vec::new(),
+        };
+        if !file.is_empty() {
+            targets.insert(0, file);
+        }
+
+        let targets = targets
+            .into_iter()
+            .map(|mut target| {
+                // spell out the stages `firmware` implies, so they get defaults too
+                if !["extract", "generate", "emulate"].iter().any(|stage| target.contains_key(*stage)) {
+                    if let Some(firmware) = target.get("firmware").cloned() {
+                        let mut extract = toml::value::Table::new();
+                        extract.insert("firmware".to_string(), firmware);
+                        target.insert("extract".to_string(), toml::Value::Table(extract));
+                        target.insert("generate".to_string(), toml::Value::Table(toml::value::Table::new()));
+                        target.insert("emulate".to_string(), toml::Value::Table(toml::value::Table::new()));
+                    }
+                }
+                // defaults only fill stages a target has, they add none
+                for (stage, default) in &defaults {
+                    if let (Some(toml::Value::Table(stage)), toml::Value::Table(default)) = (target.get_mut(stage), default) {
+                        inherit(stage, default);
+                    }
+                }
+                toml::Value::Table(target).try_into()
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Tasks { config, targets })
+    }
+
+    /// An uplink can only join one bridge, so host-bridge targets using it
+    /// cannot run side by side
+    fn check_uplink(&self, config: &Config, jobs: usize) -> Result<()> {
+        let host_bridge = self.targets.iter()
+            .any(|target| target.stages().2.is_some_and(|emulate| emulate.network == NetworkMode::HostBridge));
+        if jobs > 1 && host_bridge && config.uplink.is_some() {
+            return Err(Error::Invalid(format!(
+                "The uplink {} can only join one bridge, run host-bridge targets with jobs = 1",
+                config.uplink.as_deref().unwrap_or_default())));
+        }
+        Ok(())
+    }
+
+    /// Run the targets, `jobs` of the config at a time, and print which
+    /// stages worked for which. A failed target does not stop the others.
+    /// Each job slot emulates on its own tap and subnet; with more than one
+    /// the runs are headless and log to `<output_dir>/<target>.<start time>.serial.log`.
+    pub fn run(&self) -> Result<()> {
+        let config = Config::get()?;
+        let jobs = config.jobs.min(self.targets.len()).max(1);
+        self.check_uplink(config, jobs)?;
+        // all slots up front, a bad host_ip fails before anything runs
+        let slots = (0..jobs).map(|slot| Instance::slot(config, slot)).collect::<Result<Vec<_>>>()?;
+        if jobs > 1 {
+            std::fs::create_dir_all(&config.output_dir)
+                .map_err(|err| Error::io(format!("Failed to create {}", config.output_dir), err))?;
+        }
+
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(vec![None; self.targets.len()]);
+        std::thread::scope(|scope| {
+            for slot in &slots {
+                let (next, results) = (&next, &results);
+                scope.spawn(move || {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(target) = self.targets.get(index) else {
+                            break;
+                        };
+                        let label = target.label();
+                        let mut instance = slot.clone();
+                        if jobs > 1 {
+                            let log = Path::new(&config.output_dir).join(format!("{}.{}.serial.log", label, run_stamp()));
+                            println!("==> {} on {}", label, instance.tap);
+                            instance.console = Console::Headless { log, tee: false };
+                        } else {
+                            println!("==> {}", label);
+                        }
+                        let started = Instant::now();
+                        let outcome = target.run_on(&instance);
+                        results.lock().unwrap_or_else(|err| err.into_inner())[index] =
+                            Some((label, outcome.status, outcome.verdict, started.elapsed()));
+                    }
+                });
+            }
+        });
+        let summary: Vec<(String, [StageStatus; 3], Option<BootVerdict>, Duration)> =
+            results.into_inner().unwrap_or_else(|err| err.into_inner()).into_iter().flatten().collect();
+
+        let width = summary.iter().map(|(label, ..)| label.len()).max().unwrap_or(0).max("target".len()) + 2;
+        println!("{:<width$}{:<10}{:<10}{:<10}{:<14}time", "target", "extract", "generate", "emulate", "boot");
+        for (label, status, verdict, elapsed) in &summary {
+            println!("{:<width$}{:<10}{:<10}{:<10}{:<14}{}s", label,
+                status[0].to_str(), status[1].to_str(), status[2].to_str(),
+                verdict.as_ref().map_or("-", BootVerdict::short), elapsed.as_secs());
+        }
+
+        let failed = summary.iter().filter(|(_, status, ..)| status.contains(&StageStatus::Failed)).count();
+        if failed > 0 {
+            return Err(Error::Invalid(format!("{} of {} targets failed", failed, summary.len())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(content: &str) -> toml::value::Table {
+        toml::de::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn inherit_fills_missing_keys_table_by_table() {
+        let mut target = table(r#"
+            image = "own.qcow2"
+            [guest_network]
+            bridge = "br-lan"
+        "#);
+        inherit(&mut target, &table(r#"
+            image = "default.qcow2"
+            rootless = true
+            [guest_network]
+            bridge = "br0"
+            network_type = "Normal"
+        "#));
+        assert_eq!(target, table(r#"
+            image = "own.qcow2"
+            rootless = true
+            [guest_network]
+            bridge = "br-lan"
+            network_type = "Normal"
+        "#));
+    }
+
+    #[test]
+    fn defaults_fill_the_stages_of_each_target() {
+        let tasks = Tasks::parse(r#"
+            [config]
+            jobs = 2
+
+            [defaults.generate]
+            type_image = "Raw"
+            rootless = true
+            size = "256M"
+
+            [defaults.emulate]
+            boot_timeout = 120
+            headless = true
+
+            [[targets]]
+            name = "DIR825"
+            extract = { firmware = "DIR825.bin" }
+            generate = { image = "DIR825.raw", size = "1G" }
+
+            [[targets]]
+            emulate = { image = "R6300v2.qcow2", headless = false }
+        "#).unwrap();
+        assert_eq!(tasks.config.unwrap().jobs, Some(2));
+        assert_eq!(tasks.targets.len(), 2);
+
+        let dir825 = &tasks.targets[0];
+        assert_eq!(dir825.label(), "DIR825");
+        let generate = dir825.generate.as_ref().unwrap();
+        assert_eq!(generate.image.as_deref(), Some("DIR825.raw"));
+        assert_eq!(generate.type_image.to_str(), "raw");
+        assert!(generate.rootless);
+        assert_eq!(generate.size.as_deref(), Some("1G"));
+        // defaults add no stage a target lacks
+        assert!(dir825.emulate.is_none());
+
+        let r6300 = &tasks.targets[1];
+        assert_eq!(r6300.label(), "R6300v2.qcow2");
+        assert!(r6300.extract.is_none() && r6300.generate.is_none());
+        let emulate = r6300.emulate.as_ref().unwrap();
+        assert_eq!(emulate.boot_timeout, Some(120));
+        assert!(!emulate.headless);
+    }
+
+    #[test]
+    fn firmware_only_target_gets_every_stage() {
+        let tasks = Tasks::parse(r#"
+            [defaults.generate]
+            rootless = true
+            [defaults.emulate]
+            timeout = 60
+
+            [[targets]]
+            firmware = "../firmwares/DIR825.bin"
+        "#).unwrap();
+        let target = &tasks.targets[0];
+        assert_eq!(target.label(), "DIR825.bin");
+        assert_eq!(target.extract.as_ref().unwrap().firmware, "../firmwares/DIR825.bin");
+        assert!(target.generate.as_ref().unwrap().rootless);
+        assert_eq!(target.emulate.as_ref().unwrap().timeout, Some(60));
+
+        let (extract, generate, emulate) = target.stages();
+        assert_eq!(extract.unwrap().firmware, "../firmwares/DIR825.bin");
+        assert!(generate.is_some() && emulate.is_some());
+    }
+
+    #[test]
+    fn firmware_with_a_stage_keeps_to_it() {
+        let tasks = Tasks::parse(r#"
+            [[targets]]
+            firmware = "DIR825.bin"
+            generate = { arch = "Mips" }
+        "#).unwrap();
+        let (extract, generate, emulate) = tasks.targets[0].stages();
+        assert_eq!(extract.unwrap().firmware, "DIR825.bin");
+        assert_eq!(generate.unwrap().arch, Some(Arch::Mips));
+        assert!(emulate.is_none());
+    }
+
+    #[test]
+    fn top_level_stages_make_a_target() {
+        let tasks = Tasks::parse(r#"
+            [defaults.emulate]
+            debug = true
+
+            [extract]
+            firmware = "single.bin"
+            [emulate]
+            image = "single.qcow2"
+
+            [[targets]]
+            firmware = "other.bin"
+        "#).unwrap();
+        assert_eq!(tasks.targets.len(), 2);
+        assert_eq!(tasks.targets[0].label(), "single.bin");
+        assert!(tasks.targets[0].emulate.as_ref().unwrap().debug);
+        assert_eq!(tasks.targets[1].label(), "other.bin");
+    }
+
+    #[test]
+    fn config_paths_are_taken_from_the_task_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let task_file = dir.path().join("tasks.toml");
+        std::fs::write(&task_file, "[config]\nassets = \"..\"\noutput_dir = \"/data/outputs\"\n").unwrap();
+        let config = Tasks::load(task_file.to_str().unwrap()).unwrap().config.unwrap();
+        assert_eq!(config.assets, Some(dir.path().join("..").to_string_lossy().into_owned()));
+        assert_eq!(config.output_dir.as_deref(), Some("/data/outputs"));
+    }
+
+    #[test]
+    fn uplink_is_rejected_for_parallel_host_bridges() {
+        let tasks = Tasks::parse(r#"
+            [[targets]]
+            emulate = { image = "a.qcow2", network = "HostBridge" }
+            [[targets]]
+            emulate = { image = "b.qcow2" }
+        "#).unwrap();
+        let config = Config { uplink: Some("eth1".to_string()), ..Config::default() };
+        assert!(matches!(tasks.check_uplink(&config, 2), Err(Error::Invalid(_))));
+        assert!(tasks.check_uplink(&config, 1).is_ok());
+        assert!(tasks.check_uplink(&Config::default(), 2).is_ok());
+
+        let taps = Tasks::parse("[[targets]]\nfirmware = \"a.bin\"\n[[targets]]\nfirmware = \"b.bin\"").unwrap();
+        assert!(taps.check_uplink(&config, 2).is_ok());
+    }
+
+    #[test]
+    fn invalid_task_files_are_rejected() {
+        assert!(Tasks::parse("[[targets]]\nextract = { directory = \"out\" }").is_err());
+        assert!(Tasks::parse("[defaults.generate]\nrootless = \"yes\"\n[[targets]]\ngenerate = {}").is_err());
+        assert!(Tasks::parse("targets = 3").is_err());
+        assert!(Tasks::parse("").unwrap().targets.is_empty());
+    }
+}
@@ -2,9 +2,14 @@
 /// Clean functions
 pub use crate::generator::utils::umount_temp_images;
 pub use crate::generator::utils::disconnect_nbd_divices;
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(clap::ValueEnum, Clone, Debug, Deserialize, Serialize)]
+use crate::emulator::NetworkPlan;
+use crate::error::{Error, Result};
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Arch {
     Arm,
     Mips,
@@ -14,7 +19,7 @@ pub enum Arch {
 impl std::str::FromStr for Arch {
     type Err = String;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             "arm" => Ok(Arch::Arm),
             "mips" => Ok(Arch::Mips),
@@ -24,6 +29,8 @@ impl std::str::FromStr for Arch {
     }
 }
 impl Arch {
+    pub const ALL: [Arch; 3] = [Arch::Arm, Arch::Mips, Arch::Mipsel];
+
     pub fn to_str(&self) -> &str {
         match self {
             Arch::Arm => "arm",
@@ -33,17 +40,36 @@ impl Arch {
     }
 }
 
-#[derive(clap::ValueEnum, Clone, Debug, Deserialize, Serialize)]
+#[derive(clap::ValueEnum, Clone, Debug, Default, Deserialize, Serialize)]
 pub enum ImageType {
+    #[default]
     Qcow2,
-    #[allow(dead_code)]
     Raw
 }
 
+impl ImageType {
+    pub fn to_str(&self) -> &str {
+        match self {
+            ImageType::Qcow2 => "qcow2",
+            ImageType::Raw => "raw"
+        }
+    }
+
+    /// Tell the format of an existing image from its header
+    pub fn detect(image: &str) -> ImageType {
+        let mut magic = [0u8; 4];
+        let is_qcow2 = std::fs::File::open(image)
+            .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+            .map(|_| magic == *b"QFI\xfb")
+            .unwrap_or(false);
+        if is_qcow2 { ImageType::Qcow2 } else { ImageType::Raw }
+    }
+}
+
 impl std::str::FromStr for ImageType {
     type Err = String;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             "qcow2" => Ok(ImageType::Qcow2),
             "raw" => Ok(ImageType::Raw),
@@ -52,33 +78,152 @@ impl std::str::FromStr for ImageType {
     }
 }
 
-/// Commands for task-file
+/// Parse a size like `4096`, `64K`, `512M` or `2G` (binary units) into bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match s[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        unit => return Err(Error::parse(format!("size {}", s), format!("unknown unit {}", unit))),
+    };
+    let value: u64 = digits.trim().parse().map_err(|err| Error::parse(format!("size {}", s), err))?;
+    value.checked_shl(shift).filter(|bytes| bytes >> shift == value)
+        .ok_or_else(|| Error::parse(format!("size {}", s), "too large"))
+}
+
+/// What the guest network script does in the guest-bridge network mode,
+/// the actions of FirmAE's network.sh. For all but `Default` preInit.sh
+/// starts the firmware's rc scripts to set up the bridge waited for.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum GuestNetworkType {
+    /// create the bridge and put eth0 into it
+    #[default]
+    Default,
+    /// wait for the firmware's bridge and keep its address
+    Normal,
+    /// wait for the firmware's bridge and give it the guest address
+    Reload,
+    /// `Normal`, taking eth0 from whatever bridge grabbed it
+    Bridge,
+    /// `Reload`, taking eth0 from whatever bridge grabbed it
+    BridgeReload,
+    /// leave the network to the firmware's rc scripts
+    Off,
+}
+
+impl GuestNetworkType {
+    /// As written to `/firmadyne/network_type`
+    pub fn to_str(&self) -> &str {
+        match self {
+            GuestNetworkType::Default => "default",
+            GuestNetworkType::Normal => "normal",
+            GuestNetworkType::Reload => "reload",
+            GuestNetworkType::Bridge => "bridge",
+            GuestNetworkType::BridgeReload => "bridgereload",
+            GuestNetworkType::Off => "None",
+        }
+    }
+}
+
+/// Settings of the guest network script, written into the image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct GuestNetwork {
+    pub network_type: GuestNetworkType,
+    /// bridge inside the guest, the firmware's own for all but `Default`
+    pub bridge: String,
+    /// LAN interface brought up with the bridge
+    pub interface: String,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Extract {
-    pub firmware: String,
-    pub directory: String,
+impl Default for GuestNetwork {
+    fn default() -> Self {
+        GuestNetwork { network_type: GuestNetworkType::Default, bridge: "br0".to_string(), interface: "eth0".to_string() }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Generate {
-    pub rootfs: String,
-    pub image: String,
-    pub type_image: ImageType,
-    pub arch: Arch,
+/// Facts about a generated image, kept next to it as `<image>.info.toml`
+/// so later commands do not have to be told again
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ImageInfo {
+    pub arch: Option<Arch>,
+    /// VM snapshots saved into the image, by name, with the machine profile
+    /// they were taken with and have to be loaded with
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub snapshots: BTreeMap<String, String>,
+    /// settings of the guest network script, None for images generated
+    /// before there was one
+    pub guest_network: Option<GuestNetwork>,
+    /// what the last learning boot found, for the `Learned` network mode
+    pub learned_network: Option<NetworkPlan>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Emulate {
-    /// image regarded as root filesystem, qcow2 or raw image
-    pub image: String,
-    pub arch: Arch,
-    pub debug: bool,
+impl ImageInfo {
+    pub fn path_for(image: &str) -> String {
+        format!("{}.info.toml", image)
+    }
+
+    pub fn load(image: &str) -> Option<Self> {
+        let content = std::fs::read_to_string(Self::path_for(image)).ok()?;
+        toml::de::from_str(&content).ok()
+    }
+
+    pub fn save(&self, image: &str) -> Result<()> {
+        let content = toml::to_string(self).map_err(|err| Error::parse("image info", err))?;
+        std::fs::write(Self::path_for(image), content)
+            .map_err(|err| Error::io(format!("Failed to write image info for {}", image), err))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Tasks {
-    pub extract: Option<Extract>,
-    pub emulate: Option<Emulate>,
-    pub generate: Option<Generate>,
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_type_is_detected_from_the_magic() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        // the start of a qcow2 v3 header, named like a raw image
+        let qcow2 = write("image.raw", b"QFI\xfb\x00\x00\x00\x03");
+        assert!(matches!(ImageType::detect(&qcow2), ImageType::Qcow2));
+        // an MBR, named like a qcow2 image
+        let mut mbr = vec![0u8; 512];
+        mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+        let raw = write("image.qcow2", &mbr);
+        assert!(matches!(ImageType::detect(&raw), ImageType::Raw));
+        assert!(matches!(ImageType::detect(&write("short", b"QFI")), ImageType::Raw));
+        assert!(matches!(ImageType::detect(&dir.path().join("missing").to_string_lossy()), ImageType::Raw));
+    }
+
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("4096B").unwrap(), 4096);
+        assert_eq!(parse_size("64K").unwrap(), 64 << 10);
+        assert_eq!(parse_size("64kb").unwrap(), 64 << 10);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("512MiB").unwrap(), 512 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_size("2gib").unwrap(), 2 << 30);
+        assert_eq!(parse_size(" 16 M ").unwrap(), 16 << 20);
+        assert_eq!(parse_size("0").unwrap(), 0);
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_sizes() {
+        for size in ["", "M", "abc", "1T", "1.5G", "-1M", "M12", "12 M B", "0x100"] {
+            assert!(matches!(parse_size(size), Err(Error::Parse { .. })), "{:?} was accepted", size);
+        }
+        // does not fit in u64 once shifted
+        assert!(parse_size("17179869184G").is_err());
+        assert!(parse_size("18446744073709551616").is_err());
+        assert_eq!(parse_size("17179869183G").unwrap(), 17_179_869_183 << 30);
+    }
+}